@@ -1,40 +1,167 @@
 use cpu_4bit_emulator::compiler::Compiler;
+use cpu_4bit_emulator::diagnostics::Renderer;
 use cpu_4bit_emulator::emulator::CpuEmulator;
+use cpu_4bit_emulator::error::EmulatorErr;
+use cpu_4bit_emulator::lexer::{ambiguous, parse_number};
+use cpu_4bit_emulator::loader::FsLoader;
 use cpu_4bit_emulator::parser::Parser;
 use cpu_4bit_emulator::port::Port;
 use cpu_4bit_emulator::register::Register;
 use cpu_4bit_emulator::rom::Rom;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use cpu_4bit_emulator::span::SourceMap;
+use std::error::Error;
+use std::io::IsTerminal;
+
+const USAGE: &str = "Usage: [command] [-D NAME[=VALUE]]... [--listing PATH] [--map PATH] \
+                     [--color auto|always|never] [file_path]";
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let mut args = std::env::args().skip(1);
+    let mut file_path = None;
+    let mut defines = Vec::new();
+    let mut listing_path = None;
+    let mut map_path = None;
+    let mut color = None;
 
-    if args.len() != 2 {
-        panic!("Invalid args. Usage: [command] [file_path]");
+    while let Some(arg) = args.next() {
+        if let Some(define) = arg.strip_prefix("-D") {
+            let define = match define {
+                "" => args.next().unwrap_or_else(|| usage("-D needs a NAME")),
+                define => define.to_string(),
+            };
+            defines.push(parse_define(&define));
+        } else if arg == "--listing" {
+            listing_path = Some(
+                args.next()
+                    .unwrap_or_else(|| usage("--listing needs a PATH")),
+            );
+        } else if arg == "--map" {
+            map_path = Some(args.next().unwrap_or_else(|| usage("--map needs a PATH")));
+        } else if arg == "--color" {
+            color = match args.next().as_deref() {
+                Some("always") => Some(true),
+                Some("never") => Some(false),
+                Some("auto") => None,
+                _ => usage("--color needs `auto`, `always` or `never`"),
+            };
+        } else if file_path.is_none() {
+            file_path = Some(arg);
+        } else {
+            usage(&format!("unexpected argument `{}`", arg));
+        }
     }
 
-    let f = BufReader::new(File::open(args.get(1).unwrap()).expect("file not found"));
-    let operations = f.lines().map(|line| line.unwrap()).collect::<Vec<String>>();
+    let file_path = file_path.unwrap_or_else(|| usage("missing file_path"));
+    // Colors are for people: off when stderr is a file or a CI log, or when
+    // `NO_COLOR` is set.
+    let color = color.unwrap_or_else(|| {
+        std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+    });
+    let renderer = Renderer::new().with_color(color);
+    let exit_with = |err: &EmulatorErr, sources: &SourceMap| -> ! {
+        eprint!("{}", report(&renderer, err, sources));
+        std::process::exit(1);
+    };
 
-    let mut parser = Parser::new(operations);
-    let tokens = match parser.parse() {
-        Ok(tokens) => tokens,
-        Err(err) => panic!("{:?}", err),
+    let mut parser = match Parser::from_file(&file_path, FsLoader) {
+        Ok(parser) => parser,
+        Err(err) => exit_with(&err, &SourceMap::new()),
     };
+    for (name, value) in defines {
+        parser = parser
+            .with_define(&name, value)
+            .unwrap_or_else(|err| exit_with(&err, &SourceMap::new()));
+    }
+    let parsed = parser.parse_all();
+    if !parsed.errors.is_empty() {
+        for err in &parsed.errors {
+            eprintln!("{}", report(&renderer, err, parser.sources()));
+        }
+        let count = parsed.errors.len();
+        eprintln!(
+            "error: could not assemble `{}` due to {} previous error{}",
+            file_path,
+            count,
+            if count == 1 { "" } else { "s" }
+        );
+        std::process::exit(1);
+    }
+    let tokens = parsed.tokens;
 
     let compiler = Compiler::new();
-    let program = match compiler.compile(tokens) {
+    let program = match compiler.assemble(tokens.clone()) {
         Ok(program) => program,
-        Err(err) => panic!("{:?}", err),
+        Err(err) => exit_with(&err, parser.sources()),
     };
+    // Only a program that assembles gets a map file.
+    if let Some(path) = map_path {
+        match compiler.symbol_map(&tokens) {
+            Ok(map) => write_output(&path, &map.to_string()),
+            Err(err) => exit_with(&err, parser.sources()),
+        }
+    }
+    if let Some(path) = listing_path {
+        write_output(&path, &compiler.listing(&program, parser.sources()));
+    }
 
-    let rom = Rom::new(program);
+    let rom = match Rom::try_new(program.code) {
+        Ok(rom) => rom,
+        Err(err) => exit_with(&err, parser.sources()),
+    };
     let register = Register::new();
     let port = Port::new(0b0000, 0b0000);
-    let mut emulator = CpuEmulator::with(register, port, rom);
+    let mut emulator = match CpuEmulator::try_new(register, port, rom) {
+        Ok(emulator) => emulator.with_spans(program.spans),
+        Err(err) => exit_with(&err, parser.sources()),
+    };
     match emulator.exec() {
         Ok(_) => (),
-        Err(err) => panic!("{:?}", err),
+        Err(err) => exit_with(&err, parser.sources()),
+    }
+}
+
+/// Renders `err` followed by the chain of errors that caused it, such as
+/// the I/O error behind a file that couldn't be read.
+fn report(renderer: &Renderer, err: &EmulatorErr, sources: &SourceMap) -> String {
+    let mut report = renderer.render(&err.into(), sources);
+    let mut cause = err.source();
+    while let Some(err) = cause {
+        report.push_str(&format!("caused by: {}\n", err));
+        cause = err.source();
+    }
+    report
+}
+
+/// Splits `NAME=VALUE` from `-D`; a bare `NAME` is defined as 1.
+fn parse_define(define: &str) -> (String, u32) {
+    let (name, value) = match define.split_once('=') {
+        Some((name, value)) => match parse_number(value) {
+            Some(value) => (name, value),
+            None if ambiguous(value) => usage(&format!(
+                "`{}` could be binary or decimal; write `0b{}` or `0d{}`",
+                value, value, value
+            )),
+            None => usage(&format!("invalid value `{}` for `{}`", value, name)),
+        },
+        None => (define, 1),
+    };
+    if let Err(err) = Parser::check_define(name) {
+        usage(&format!("invalid `-D {}`: {}", define, err.message()));
     }
+    (name.to_string(), value)
+}
+
+/// Writes a generated file, or prints it when `path` is `-`.
+fn write_output(path: &str, contents: &str) {
+    if path == "-" {
+        print!("{}", contents);
+    } else if let Err(err) = std::fs::write(path, contents) {
+        eprintln!("error: can't write `{}`: {}", path, err);
+        std::process::exit(1);
+    }
+}
+
+fn usage(problem: &str) -> ! {
+    eprintln!("error: {}\n{}", problem, USAGE);
+    std::process::exit(2);
 }
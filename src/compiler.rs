@@ -1,150 +1,1012 @@
 use crate::error::EmulatorErr;
-use crate::token::{Register, Token};
+use crate::op::{Opcode, OperandKinds};
+use crate::rom::ROM_SIZE;
+use crate::span::{SourceMap, Span};
+use crate::token::{Operand, Token, TokenKind, MAX_CONSTANT_DEPTH};
+use std::collections::HashMap;
+use std::fmt;
 
-pub struct Compiler;
+pub struct Compiler {
+    fill: u8,
+}
+
+/// What a name used as an operand stands for.
+enum Symbol {
+    Address(u32),
+    Constant(Operand),
+    /// A label that the first pass hasn't reached yet, so that a `.org`
+    /// using it can say so.
+    Unplaced,
+}
+
+/// Machine code together with the source span of every emitted byte.
+#[derive(Debug)]
+pub struct Program {
+    pub code: Vec<u8>,
+    pub spans: Vec<Span>,
+}
+
+/// Every label and constant of a program, as written to a map file.
+#[derive(Debug, Default)]
+pub struct SymbolMap {
+    /// Sorted by address.
+    pub labels: Vec<LabelEntry>,
+    /// Sorted by name.
+    pub constants: Vec<ConstantEntry>,
+}
+
+#[derive(Debug)]
+pub struct LabelEntry {
+    pub name: String,
+    pub address: u32,
+    pub span: Span,
+    /// The addresses of the `jmp` and `jnc` instructions that target the
+    /// label.
+    pub jumps: Vec<u32>,
+}
+
+#[derive(Debug)]
+pub struct ConstantEntry {
+    pub name: String,
+    pub value: i64,
+    pub span: Span,
+}
 
 impl Default for Compiler {
     fn default() -> Self {
-        Self
+        Self::new()
     }
 }
 
 impl Compiler {
     pub fn new() -> Self {
-        Self::default()
+        Self { fill: 0 }
+    }
+
+    /// Sets the byte written to the addresses that `.org` skips over. The
+    /// default, `0b0000_0000`, is `add A 0`, which does nothing.
+    pub fn with_fill(mut self, fill: u8) -> Self {
+        self.fill = fill;
+        self
     }
 
     pub fn compile(&self, tokens: Vec<Token>) -> Result<Vec<u8>, EmulatorErr> {
+        self.assemble(tokens).map(|program| program.code)
+    }
+
+    /// Like `compile`, but keeps track of where each byte came from so that
+    /// runtime errors can point back at the source.
+    pub fn assemble(&self, tokens: Vec<Token>) -> Result<Program, EmulatorErr> {
         if tokens.is_empty() {
-            return Err(EmulatorErr::new(
-                "Failed to start to compile because token list is empty.",
-            ));
+            return Err(EmulatorErr::Assembly {
+                msg: "Failed to start to compile because token list is empty.".to_string(),
+                span: None,
+                annotations: Box::default(),
+            });
+        }
+
+        let (symbols, addresses) = self.collect_symbols(&tokens)?;
+        self.check_rom_size(&tokens, &addresses)?;
+        let mut code = Vec::new();
+        let mut spans = Vec::new();
+        let mut placed = Vec::new();
+        let mut gap = Span::default();
+
+        for (token, address) in tokens.into_iter().zip(addresses) {
+            let span = token.span;
+            let program = match token.kind {
+                TokenKind::Label(_) | TokenKind::Constant(..) => continue,
+                TokenKind::Org(_) => {
+                    gap = span;
+                    continue;
+                }
+                TokenKind::Db(value) => self.resolve(value, &symbols, &span, 8)?,
+                kind => {
+                    let (opcode, im) = kind.instruction().expect("an instruction");
+                    let im = match im {
+                        Some(im) => self.resolve(im.clone(), &symbols, &span, 4)?,
+                        None => 0,
+                    };
+                    opcode.encode(im)
+                }
+            };
+
+            // Addresses skipped by `.org` are padded with the fill byte and
+            // blamed on the `.org` that skipped them.
+            let address = address as usize;
+            while code.len() <= address {
+                code.push(self.fill);
+                spans.push(gap.clone());
+                placed.push(false);
+            }
+            if placed[address] {
+                return Err(EmulatorErr::assembly(
+                    &format!(
+                        "address {} is already used by the code at {}",
+                        address, spans[address]
+                    ),
+                    span,
+                )
+                .with_label(spans[address].clone(), "first placed here"));
+            }
+            code[address] = program;
+            spans[address] = span;
+            placed[address] = true;
+        }
+
+        Ok(Program { code, spans })
+    }
+
+    /// Lays out an assembled program one ROM word per row: the address,
+    /// the word in binary (as set on the DIP switches) and hex, the
+    /// instruction it decodes to, and the source line it came from.
+    pub fn listing(&self, program: &Program, sources: &SourceMap) -> String {
+        let mut listing = String::from("addr        word          instruction  source\n");
+
+        for (address, (&word, span)) in program.code.iter().zip(&program.spans).enumerate() {
+            let instruction = match Opcode::decode(word) {
+                Some((opcode, im)) => opcode.assembly(im),
+                None => format!(".db 0x{:02x}", word),
+            };
+            let source = match sources.line(span) {
+                Some(line) => format!("{}:{}  {}", span.file, span.line, line.trim()),
+                None => String::new(),
+            };
+            listing.push_str(
+                format!(
+                    "{:>2}  {:04b}    {:04b} {:04b} {:02x}  {:<11}  {}",
+                    address,
+                    address,
+                    word >> 4,
+                    word & 0x0f,
+                    word,
+                    instruction,
+                    source
+                )
+                .trim_end(),
+            );
+            listing.push('\n');
         }
 
-        let mut result = Vec::new();
+        listing
+    }
+
+    /// Lists the labels and constants of a program, along with the jumps to
+    /// each label. A jump is credited to every label at the address it
+    /// targets, however the target is written. Every constant is evaluated,
+    /// including unused ones. The `__`-prefixed labels that the parser
+    /// generates for pseudo-instructions and expansions are left out.
+    pub fn symbol_map(&self, tokens: &[Token]) -> Result<SymbolMap, EmulatorErr> {
+        let (symbols, addresses) = self.collect_symbols(tokens)?;
+        let mut map = SymbolMap::default();
 
         for token in tokens {
-            let program = match token {
-                Token::Mov(Register::A, im) => self.gen_bin_code(0b0011, im),
-                Token::Mov(Register::B, im) => self.gen_bin_code(0b0111, im),
-                Token::MovAB => self.gen_bin_code_with_zero_padding(0b0001),
-                Token::MovBA => self.gen_bin_code_with_zero_padding(0b0100),
-                Token::Add(Register::A, im) => self.gen_bin_code(0b0000, im),
-                Token::Add(Register::B, im) => self.gen_bin_code(0b0101, im),
-                Token::Jmp(im) => self.gen_bin_code(0b1111, im),
-                Token::Jnc(im) => self.gen_bin_code(0b1110, im),
-                Token::In(Register::A) => self.gen_bin_code_with_zero_padding(0b0010),
-                Token::In(Register::B) => self.gen_bin_code_with_zero_padding(0b0110),
-                Token::OutB => self.gen_bin_code_with_zero_padding(0b1001),
-                Token::OutIm(im) => self.gen_bin_code(0b1011, im),
+            match &token.kind {
+                TokenKind::Label(name) if name.starts_with("__") => {}
+                TokenKind::Label(name) => {
+                    let address = match symbols.get(name) {
+                        Some(Symbol::Address(address)) => *address,
+                        _ => continue,
+                    };
+                    map.labels.push(LabelEntry {
+                        name: name.clone(),
+                        address,
+                        span: token.span.clone(),
+                        jumps: Vec::new(),
+                    });
+                }
+                TokenKind::Constant(name, _) => {
+                    let value = self.symbol_value(name, &symbols, &token.span, &mut Vec::new())?;
+                    map.constants.push(ConstantEntry {
+                        name: name.clone(),
+                        value,
+                        span: token.span.clone(),
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        for (token, &address) in tokens.iter().zip(&addresses) {
+            let target = match token.kind.instruction() {
+                Some((opcode, Some(target))) if opcode.info().operands == OperandKinds::Address => {
+                    target
+                }
+                _ => continue,
             };
-            result.push(program);
+            let target = u32::from(self.resolve(target.clone(), &symbols, &token.span, 4)?);
+            for label in map
+                .labels
+                .iter_mut()
+                .filter(|label| label.address == target)
+            {
+                label.jumps.push(address);
+            }
+        }
+
+        map.labels
+            .sort_by(|a, b| (a.address, &a.name).cmp(&(b.address, &b.name)));
+        map.constants.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(map)
+    }
+
+    /// Fails if any code is placed past the last address of the ROM, naming
+    /// every instruction that would land there.
+    fn check_rom_size(&self, tokens: &[Token], addresses: &[u32]) -> Result<(), EmulatorErr> {
+        let overflowing: Vec<(u32, &Span)> = tokens
+            .iter()
+            .zip(addresses)
+            .filter(|(token, &address)| token.kind.is_instruction() && address as usize >= ROM_SIZE)
+            .map(|(token, &address)| (address, &token.span))
+            .collect();
+
+        let first = match overflowing.first() {
+            None => return Ok(()),
+            Some((_, first)) => (*first).clone(),
+        };
+        let size = overflowing
+            .iter()
+            .map(|(address, _)| *address as usize + 1)
+            .max()
+            .unwrap_or(0);
+        let msg = format!(
+            "program needs {} words but the ROM only holds {}; {} instruction(s) past address {} at {}",
+            size,
+            ROM_SIZE,
+            overflowing.len(),
+            ROM_SIZE - 1,
+            overflowing
+                .iter()
+                .map(|(_, span)| span.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let mut err = EmulatorErr::RomOverflow {
+            size,
+            msg,
+            span: Some(first),
+            annotations: Box::default(),
+        };
+        for (_, span) in &overflowing[1..] {
+            err = err.with_label((*span).clone(), "also past the end of the ROM");
+        }
+        Err(err)
+    }
+
+    /// First pass: works out the address of every token, assigns every label
+    /// the address of the instruction that follows it and gathers the
+    /// constants. A `.org` address may use constants and the labels defined
+    /// before it.
+    fn collect_symbols(
+        &self,
+        tokens: &[Token],
+    ) -> Result<(HashMap<String, Symbol>, Vec<u32>), EmulatorErr> {
+        let mut symbols = HashMap::new();
+        let mut defined = HashMap::new();
+        let mut addresses = Vec::with_capacity(tokens.len());
+        let mut address: u32 = 0;
+
+        for token in tokens {
+            if let TokenKind::Constant(name, value) = &token.kind {
+                Self::define(
+                    &mut symbols,
+                    &mut defined,
+                    name,
+                    Symbol::Constant(value.clone()),
+                    &token.span,
+                )?;
+            }
+        }
+        for token in tokens {
+            if let TokenKind::Label(name) = &token.kind {
+                symbols.entry(name.clone()).or_insert(Symbol::Unplaced);
+            }
+        }
+
+        for token in tokens {
+            addresses.push(address);
+            match &token.kind {
+                TokenKind::Label(name) => Self::define(
+                    &mut symbols,
+                    &mut defined,
+                    name,
+                    Symbol::Address(address),
+                    &token.span,
+                )?,
+                TokenKind::Org(operand) => {
+                    let value = operand.evaluate(&token.span, &mut |name| {
+                        self.symbol_value(name, &symbols, &token.span, &mut Vec::new())
+                    })?;
+                    if !(0..ROM_SIZE as i64).contains(&value) {
+                        return Err(EmulatorErr::assembly(
+                            &format!(
+                                "`.org` address ({}) is outside the ROM, expected 0 to {}",
+                                value,
+                                ROM_SIZE - 1
+                            ),
+                            token.span.clone(),
+                        ));
+                    }
+                    address = value as u32;
+                }
+                TokenKind::Constant(..) => {}
+                _ => address += 1,
+            }
+        }
+
+        Ok((symbols, addresses))
+    }
+
+    /// Records `symbol` under `name`, remembering where it was defined so
+    /// that a second definition can point back at the first.
+    fn define(
+        symbols: &mut HashMap<String, Symbol>,
+        defined: &mut HashMap<String, Span>,
+        name: &str,
+        symbol: Symbol,
+        span: &Span,
+    ) -> Result<(), EmulatorErr> {
+        let what = match symbol {
+            Symbol::Constant(_) => "constant",
+            _ => "label",
+        };
+        if let Some(first) = defined.get(name) {
+            return Err(EmulatorErr::assembly(
+                &format!("{} `{}` is already defined at {}", what, name, first),
+                span.clone(),
+            )
+            .with_label(first.clone(), "first defined here"));
+        }
+        symbols.insert(name.to_string(), symbol);
+        defined.insert(name.to_string(), span.clone());
+        Ok(())
+    }
+
+    /// Works out the value of an operand and checks that it fits in a field
+    /// of `bits` bits: the 4-bit immediate of an instruction or a whole byte.
+    fn resolve(
+        &self,
+        operand: Operand,
+        symbols: &HashMap<String, Symbol>,
+        span: &Span,
+        bits: u32,
+    ) -> Result<u8, EmulatorErr> {
+        let what = match &operand {
+            Operand::Literal(_, text) => format!("immediate `{}`", text),
+            Operand::Symbol(name) => match symbols.get(name) {
+                Some(Symbol::Address(_)) => format!("jump target `{}`", name),
+                _ => format!("constant `{}`", name),
+            },
+            expression => format!("expression `{}`", expression),
+        };
+        let value = operand.evaluate(span, &mut |name| {
+            self.symbol_value(name, symbols, span, &mut Vec::new())
+        })?;
+
+        let max = (1 << bits) - 1;
+        if !(0..=max).contains(&value) {
+            return Err(EmulatorErr::assembly(
+                &format!(
+                    "{} ({}) does not fit in {} bits, expected 0 to {}",
+                    what, value, bits, max
+                ),
+                span.clone(),
+            ));
         }
 
-        Ok(result)
+        Ok(value as u8)
     }
 
-    fn gen_bin_code(&self, op: u8, im: u8) -> u8 {
-        let shift_op = op << 4;
-        let shift_data = im & 0x0f;
-        shift_op | shift_data
+    /// Follows a symbol down to a number. `visiting` holds the constants
+    /// being evaluated, to catch definitions that refer back to themselves.
+    fn symbol_value(
+        &self,
+        name: &str,
+        symbols: &HashMap<String, Symbol>,
+        span: &Span,
+        visiting: &mut Vec<String>,
+    ) -> Result<i64, EmulatorErr> {
+        match symbols.get(name) {
+            Some(Symbol::Address(address)) => Ok(i64::from(*address)),
+            Some(Symbol::Constant(value)) => {
+                if visiting.iter().any(|visited| visited == name) {
+                    return Err(EmulatorErr::assembly(
+                        &format!("constant `{}` is defined in terms of itself", name),
+                        span.clone(),
+                    ));
+                }
+                if visiting.len() >= MAX_CONSTANT_DEPTH {
+                    return Err(EmulatorErr::assembly(
+                        &format!(
+                            "constant `{}` is defined through more than {} other constants",
+                            name, MAX_CONSTANT_DEPTH
+                        ),
+                        span.clone(),
+                    ));
+                }
+                visiting.push(name.to_string());
+                let value = value.evaluate(span, &mut |name| {
+                    self.symbol_value(name, symbols, span, visiting)
+                })?;
+                visiting.pop();
+                Ok(value)
+            }
+            Some(Symbol::Unplaced) => Err(EmulatorErr::assembly(
+                &format!("`.org` address uses label `{}` before it is defined", name),
+                span.clone(),
+            )
+            .with_help("a `.org` address may only use constants and labels defined before it")),
+            None => Err(EmulatorErr::assembly(
+                &format!("undefined symbol `{}`", name),
+                span.clone(),
+            )),
+        }
     }
+}
 
-    #[allow(clippy::erasing_op)]
-    fn gen_bin_code_with_zero_padding(&self, op: u8) -> u8 {
-        let shift_op = op << 4;
-        let zero_padding = 0b0000 & 0x0f;
-        shift_op | zero_padding
+/// One symbol per line: labels with their address, where they are defined
+/// and the addresses that jump to them, then constants with their value.
+impl fmt::Display for SymbolMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "; label, address, defined at, jumped to from")?;
+        for label in &self.labels {
+            let jumps: Vec<String> = label.jumps.iter().map(|jump| jump.to_string()).collect();
+            let jumps = if jumps.is_empty() {
+                "-".to_string()
+            } else {
+                jumps.join(",")
+            };
+            writeln!(
+                f,
+                "label {} {} {} {}",
+                label.name, label.address, label.span, jumps
+            )?;
+        }
+        writeln!(f, "; constant, value, defined at")?;
+        for constant in &self.constants {
+            writeln!(
+                f,
+                "constant {} {} {}",
+                constant.name, constant.value, constant.span
+            )?;
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod compiler_tests {
     use crate::compiler::Compiler;
-    use crate::token::Register;
-    use crate::token::Token::{Add, In, Jmp, Jnc, Mov, MovAB, MovBA, OutB, OutIm};
+    use crate::error::EmulatorErr;
+    use crate::span::{SourceMap, Span};
+    use crate::token::TokenKind::{
+        Add, Constant, Db, In, Jmp, Jnc, Label, Mov, MovAB, MovBA, Org, OutB, OutIm,
+    };
+    use crate::token::{BinaryOp, Operand, Register, Token, UnaryOp};
+    use std::sync::Arc;
 
     #[test]
     fn test_compile_mov_a() {
         let compiler = Compiler::new();
-        let program = compiler.compile(vec![Mov(Register::A, 1)]);
+        let program = compiler.compile(vec![Mov(Register::A, 1.into()).into()]);
         assert_eq!(program.unwrap(), vec![0b00110001]);
     }
 
     #[test]
     fn test_compile_mov_b() {
         let compiler = Compiler::new();
-        let program = compiler.compile(vec![Mov(Register::B, 1)]);
+        let program = compiler.compile(vec![Mov(Register::B, 1.into()).into()]);
         assert_eq!(program.unwrap(), vec![0b01110001]);
     }
 
     #[test]
     fn test_compile_mov_ab() {
         let compiler = Compiler::new();
-        let program = compiler.compile(vec![MovAB]);
+        let program = compiler.compile(vec![MovAB.into()]);
         assert_eq!(program.unwrap(), vec![0b00010000]);
     }
 
     #[test]
     fn test_compile_mov_ba() {
         let compiler = Compiler::new();
-        let program = compiler.compile(vec![MovBA]);
+        let program = compiler.compile(vec![MovBA.into()]);
         assert_eq!(program.unwrap(), vec![0b01000000]);
     }
 
     #[test]
     fn test_compile_add_a() {
         let compiler = Compiler::new();
-        let program = compiler.compile(vec![Add(Register::A, 1)]);
+        let program = compiler.compile(vec![Add(Register::A, 1.into()).into()]);
         assert_eq!(program.unwrap(), vec![0b00000001]);
     }
 
     #[test]
     fn test_compile_add_b() {
         let compiler = Compiler::new();
-        let program = compiler.compile(vec![Add(Register::B, 1)]);
+        let program = compiler.compile(vec![Add(Register::B, 1.into()).into()]);
         assert_eq!(program.unwrap(), vec![0b01010001]);
     }
 
     #[test]
     fn test_compile_jmp() {
         let compiler = Compiler::new();
-        let program = compiler.compile(vec![Jmp(1)]);
+        let program = compiler.compile(vec![Jmp(1.into()).into()]);
         assert_eq!(program.unwrap(), vec![0b11110001]);
     }
 
     #[test]
     fn test_compile_jnc() {
         let compiler = Compiler::new();
-        let program = compiler.compile(vec![Jnc(1)]);
+        let program = compiler.compile(vec![Jnc(1.into()).into()]);
         assert_eq!(program.unwrap(), vec![0b11100001]);
     }
 
     #[test]
     fn test_compile_in_a() {
         let compiler = Compiler::new();
-        let program = compiler.compile(vec![In(Register::A)]);
+        let program = compiler.compile(vec![In(Register::A).into()]);
         assert_eq!(program.unwrap(), vec![0b00100000]);
     }
 
     #[test]
     fn test_compile_in_b() {
         let compiler = Compiler::new();
-        let program = compiler.compile(vec![In(Register::B)]);
+        let program = compiler.compile(vec![In(Register::B).into()]);
         assert_eq!(program.unwrap(), vec![0b01100000]);
     }
 
     #[test]
     fn test_compile_out_b() {
         let compiler = Compiler::new();
-        let program = compiler.compile(vec![OutB]);
+        let program = compiler.compile(vec![OutB.into()]);
         assert_eq!(program.unwrap(), vec![0b10010000]);
     }
 
     #[test]
     fn test_compile_out_im() {
         let compiler = Compiler::new();
-        let program = compiler.compile(vec![OutIm(1)]);
+        let program = compiler.compile(vec![OutIm(1.into()).into()]);
         assert_eq!(program.unwrap(), vec![0b10110001]);
     }
+
+    #[test]
+    fn test_compile_labels() {
+        let compiler = Compiler::new();
+        let program = compiler.compile(vec![
+            Jmp(Operand::Symbol("end".to_string())).into(),
+            Label("top".to_string()).into(),
+            Add(Register::A, 1.into()).into(),
+            Jnc(Operand::Symbol("top".to_string())).into(),
+            Label("end".to_string()).into(),
+            OutB.into(),
+        ]);
+        assert_eq!(
+            program.unwrap(),
+            vec![0b11110011, 0b00000001, 0b11100001, 0b10010000]
+        );
+    }
+
+    #[test]
+    fn test_compile_undefined_label() {
+        let compiler = Compiler::new();
+        let program = compiler.compile(vec![Jmp(Operand::Symbol("end".to_string())).into()]);
+        assert_eq!(program.unwrap_err().message(), "undefined symbol `end`");
+    }
+
+    #[test]
+    fn test_compile_duplicate_label() {
+        let first = Span::new(Arc::from("test.sasm"), 1, 1, 4);
+        let second = Span::new(Arc::from("test.sasm"), 3, 1, 4);
+        let err = Compiler::new()
+            .compile(vec![
+                Token::new(Label("end".to_string()), first.clone()),
+                OutB.into(),
+                Token::new(Label("end".to_string()), second.clone()),
+            ])
+            .unwrap_err();
+        assert_eq!(
+            err.message(),
+            "label `end` is already defined at test.sasm:1:1"
+        );
+        assert_eq!(err.span(), Some(&second));
+        assert_eq!(
+            err.annotations().labels,
+            vec![(first, "first defined here".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_assemble_keeps_spans() {
+        let compiler = Compiler::new();
+        let span = Span::new(Arc::from("test.sasm"), 3, 1, 9);
+        let program = compiler
+            .assemble(vec![
+                Label("top".to_string()).into(),
+                Token::new(OutIm(1.into()), span.clone()),
+            ])
+            .unwrap();
+        assert_eq!(program.code, vec![0b10110001]);
+        assert_eq!(program.spans, vec![span]);
+    }
+
+    #[test]
+    fn test_compile_error_has_span() {
+        let compiler = Compiler::new();
+        let span = Span::new(Arc::from("test.sasm"), 2, 1, 9);
+        let err = compiler
+            .compile(vec![Token::new(
+                Jmp(Operand::Symbol("end".to_string())),
+                span.clone(),
+            )])
+            .unwrap_err();
+        assert_eq!(err.span(), Some(&span));
+    }
+
+    #[test]
+    fn test_compile_immediate_out_of_range() {
+        let compiler = Compiler::new();
+        let span = Span::new(Arc::from("test.sasm"), 1, 1, 12);
+        let err = compiler
+            .compile(vec![Token::new(
+                Mov(Register::A, Operand::Literal(17, "10001".to_string())),
+                span.clone(),
+            )])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test.sasm:1:1: immediate `10001` (17) does not fit in 4 bits, expected 0 to 15"
+        );
+    }
+
+    #[test]
+    fn test_compile_program_too_large() {
+        let compiler = Compiler::new();
+        let tokens: Vec<Token> = (1..=18)
+            .map(|line| Token::new(OutB, Span::new(Arc::from("test.sasm"), line, 1, 6)))
+            .collect();
+
+        let err = compiler.compile(tokens).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test.sasm:17:1: program needs 18 words but the ROM only holds 16; \
+             2 instruction(s) past address 15 at test.sasm:17:1, test.sasm:18:1"
+        );
+        assert!(matches!(err, EmulatorErr::RomOverflow { size: 18, .. }));
+    }
+
+    #[test]
+    fn test_compile_program_fills_rom() {
+        let compiler = Compiler::new();
+        let mut tokens: Vec<Token> = (0..16).map(|_| OutB.into()).collect();
+        tokens.push(Label("end".to_string()).into());
+
+        assert_eq!(compiler.compile(tokens).unwrap().len(), 16);
+    }
+
+    #[test]
+    fn test_compile_jump_target_out_of_range() {
+        let compiler = Compiler::new();
+        let mut tokens = vec![Jmp(Operand::Symbol("end".to_string())).into()];
+        tokens.extend((0..15).map(|_| OutB.into()));
+        tokens.push(Label("end".to_string()).into());
+
+        let err = compiler.compile(tokens).unwrap_err();
+        assert_eq!(
+            err.message(),
+            "jump target `end` (16) does not fit in 4 bits, expected 0 to 15"
+        );
+    }
+
+    #[test]
+    fn test_compile_constants() {
+        let compiler = Compiler::new();
+        let program = compiler.compile(vec![
+            OutIm(Operand::Symbol("PATTERN".to_string())).into(),
+            Constant("PATTERN".to_string(), Operand::Symbol("LED".to_string())).into(),
+            Constant("LED".to_string(), 0b0110.into()).into(),
+            Jmp(Operand::Symbol("PATTERN".to_string())).into(),
+        ]);
+        assert_eq!(program.unwrap(), vec![0b10110110, 0b11110110]);
+    }
+
+    #[test]
+    fn test_compile_constant_out_of_range() {
+        let compiler = Compiler::new();
+        let err = compiler
+            .compile(vec![
+                Constant("BIG".to_string(), 0b10000.into()).into(),
+                Mov(Register::A, Operand::Symbol("BIG".to_string())).into(),
+            ])
+            .unwrap_err();
+        assert_eq!(
+            err.message(),
+            "constant `BIG` (16) does not fit in 4 bits, expected 0 to 15"
+        );
+    }
+
+    #[test]
+    fn test_compile_recursive_constant() {
+        let compiler = Compiler::new();
+        let err = compiler
+            .compile(vec![
+                Constant("X".to_string(), Operand::Symbol("Y".to_string())).into(),
+                Constant("Y".to_string(), Operand::Symbol("X".to_string())).into(),
+                OutIm(Operand::Symbol("X".to_string())).into(),
+            ])
+            .unwrap_err();
+        assert_eq!(err.message(), "constant `X` is defined in terms of itself");
+    }
+
+    #[test]
+    fn test_compile_long_constant_chain() {
+        let name = |n: usize| format!("C{}", n);
+        let mut tokens = vec![Constant(name(0), 1.into()).into()];
+        for n in 1..50_000 {
+            tokens.push(Constant(name(n), Operand::Symbol(name(n - 1))).into());
+        }
+        tokens.push(OutIm(Operand::Symbol(name(49_999))).into());
+        let err = Compiler::new().compile(tokens).unwrap_err();
+        assert!(err
+            .message()
+            .ends_with("is defined through more than 64 other constants"));
+
+        let tokens = vec![
+            Constant(name(0), 1.into()).into(),
+            Constant(name(1), Operand::Symbol(name(0))).into(),
+            OutIm(Operand::Symbol(name(1))).into(),
+        ];
+        assert_eq!(Compiler::new().compile(tokens).unwrap(), vec![0b10110001]);
+    }
+
+    #[test]
+    fn test_compile_expressions() {
+        let compiler = Compiler::new();
+        let symbol = |name: &str| Box::new(Operand::Symbol(name.to_string()));
+        let program = compiler.compile(vec![
+            Constant("MASK".to_string(), 0b0011.into()).into(),
+            Label("top".to_string()).into(),
+            OutIm(Operand::Binary(
+                BinaryOp::And,
+                Box::new(Operand::Unary(UnaryOp::Not, symbol("MASK"))),
+                Box::new(0xf.into()),
+            ))
+            .into(),
+            Jnc(Operand::Binary(
+                BinaryOp::Add,
+                symbol("top"),
+                Box::new(1.into()),
+            ))
+            .into(),
+            Mov(
+                Register::A,
+                Operand::Binary(BinaryOp::Sub, Box::new(15.into()), symbol("MASK")),
+            )
+            .into(),
+        ]);
+        assert_eq!(program.unwrap(), vec![0b10111100, 0b11100001, 0b00111100]);
+    }
+
+    #[test]
+    fn test_compile_expression_out_of_range() {
+        let compiler = Compiler::new();
+        let err = compiler
+            .compile(vec![OutIm(Operand::Binary(
+                BinaryOp::Sub,
+                Box::new(1.into()),
+                Box::new(2.into()),
+            ))
+            .into()])
+            .unwrap_err();
+        assert_eq!(
+            err.message(),
+            "expression `1 - 2` (-1) does not fit in 4 bits, expected 0 to 15"
+        );
+    }
+
+    #[test]
+    fn test_compile_division_by_zero() {
+        let compiler = Compiler::new();
+        let err = compiler
+            .compile(vec![OutIm(Operand::Binary(
+                BinaryOp::Div,
+                Box::new(1.into()),
+                Box::new(0.into()),
+            ))
+            .into()])
+            .unwrap_err();
+        assert_eq!(err.message(), "`1 / 0` can't be evaluated");
+    }
+
+    #[test]
+    fn test_compile_org_pads_with_fill() {
+        let tokens = vec![
+            OutIm(1.into()).into(),
+            Org(Operand::Symbol("START".to_string())).into(),
+            Label("start".to_string()).into(),
+            Jmp(Operand::Symbol("start".to_string())).into(),
+            Constant("START".to_string(), 3.into()).into(),
+        ];
+        let program = Compiler::new().compile(tokens.clone());
+        assert_eq!(program.unwrap(), vec![0b10110001, 0, 0, 0b11110011]);
+
+        let program = Compiler::new().with_fill(0xff).compile(tokens);
+        assert_eq!(program.unwrap(), vec![0b10110001, 0xff, 0xff, 0b11110011]);
+    }
+
+    #[test]
+    fn test_compile_org_backwards() {
+        let program = Compiler::new().compile(vec![
+            Org(2.into()).into(),
+            OutB.into(),
+            Org(0.into()).into(),
+            Jmp(2.into()).into(),
+        ]);
+        assert_eq!(program.unwrap(), vec![0b11110010, 0, 0b10010000]);
+    }
+
+    #[test]
+    fn test_compile_overlapping_placement() {
+        let span = |line| Span::new(Arc::from("test.sasm"), line, 1, 2);
+        let err = Compiler::new()
+            .compile(vec![
+                Token::new(OutB, span(1)),
+                Token::new(OutB, span(2)),
+                Token::new(Org(1.into()), span(3)),
+                Token::new(In(Register::A), span(4)),
+            ])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test.sasm:4:1: address 1 is already used by the code at test.sasm:2:1"
+        );
+    }
+
+    #[test]
+    fn test_compile_org_out_of_range() {
+        let err = Compiler::new()
+            .compile(vec![Org(16.into()).into(), OutB.into()])
+            .unwrap_err();
+        assert_eq!(
+            err.message(),
+            "`.org` address (16) is outside the ROM, expected 0 to 15"
+        );
+
+        let err = Compiler::new()
+            .compile(vec![
+                Org(Operand::Symbol("end".to_string())).into(),
+                OutB.into(),
+                Label("end".to_string()).into(),
+            ])
+            .unwrap_err();
+        assert_eq!(
+            err.message(),
+            "`.org` address uses label `end` before it is defined"
+        );
+
+        let mut tokens = vec![Org(15.into()).into()];
+        tokens.push(OutB.into());
+        tokens.push(OutB.into());
+        let err = Compiler::new().compile(tokens).unwrap_err();
+        assert!(err
+            .message()
+            .starts_with("program needs 17 words but the ROM only holds 16"));
+    }
+
+    #[test]
+    fn test_compile_db() {
+        let program = Compiler::new().compile(vec![Db(0b1000_0101.into()).into(), OutB.into()]);
+        assert_eq!(program.unwrap(), vec![0b10000101, 0b10010000]);
+
+        let err = Compiler::new()
+            .compile(vec![Db(Operand::Literal(256, "0x100".to_string())).into()])
+            .unwrap_err();
+        assert_eq!(
+            err.message(),
+            "immediate `0x100` (256) does not fit in 8 bits, expected 0 to 255"
+        );
+    }
+
+    #[test]
+    fn test_listing() {
+        let file: Arc<str> = Arc::from("led.sasm");
+        let mut sources = SourceMap::new();
+        sources.add(
+            file.clone(),
+            vec![
+                "start: out 0b0011   ; both LEDs".to_string(),
+                ".org 3".to_string(),
+                "  jmp start".to_string(),
+                ".db 0x80".to_string(),
+            ],
+        );
+        let span = |line| Span::new(file.clone(), line, 1, 2);
+        let compiler = Compiler::new();
+        let program = compiler
+            .assemble(vec![
+                Token::new(Label("start".to_string()), span(1)),
+                Token::new(OutIm(3.into()), span(1)),
+                Token::new(Org(3.into()), span(2)),
+                Token::new(Jmp(Operand::Symbol("start".to_string())), span(3)),
+                Token::new(Db(0x80.into()), span(4)),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            compiler.listing(&program, &sources),
+            "addr        word          instruction  source\n\
+             \x200  0000    1011 0011 b3  out 3        led.sasm:1  start: out 0b0011   ; both LEDs\n\
+             \x201  0001    0000 0000 00  add A, 0     led.sasm:2  .org 3\n\
+             \x202  0010    0000 0000 00  add A, 0     led.sasm:2  .org 3\n\
+             \x203  0011    1111 0000 f0  jmp 0        led.sasm:3  jmp start\n\
+             \x204  0100    1000 0000 80  .db 0x80     led.sasm:4  .db 0x80\n"
+        );
+    }
+
+    #[test]
+    fn test_symbol_map() {
+        let span = |line| Span::new(Arc::from("led.sasm"), line, 1, 2);
+        let tokens = vec![
+            Token::new(Constant("LED".to_string(), 3.into()), span(1)),
+            Token::new(
+                Constant(
+                    "ALL".to_string(),
+                    Operand::Binary(
+                        BinaryOp::Shl,
+                        Box::new(Operand::Symbol("LED".to_string())),
+                        Box::new(2.into()),
+                    ),
+                ),
+                span(2),
+            ),
+            Token::new(Label("start".to_string()), span(3)),
+            Token::new(OutIm(Operand::Symbol("LED".to_string())), span(3)),
+            Token::new(Label("wait".to_string()), span(4)),
+            Token::new(Jnc(Operand::Symbol("wait".to_string())), span(4)),
+            Token::new(Jmp(Operand::Symbol("start".to_string())), span(5)),
+            Token::new(Label("done".to_string()), span(6)),
+        ];
+
+        let map = Compiler::new().symbol_map(&tokens).unwrap();
+        assert_eq!(map.labels[1].name, "wait");
+        assert_eq!(map.labels[1].jumps, vec![1]);
+        assert_eq!(map.constants[0].value, 12);
+        assert_eq!(
+            map.to_string(),
+            "; label, address, defined at, jumped to from\n\
+             label start 0 led.sasm:3:1 2\n\
+             label wait 1 led.sasm:4:1 1\n\
+             label done 3 led.sasm:6:1 -\n\
+             ; constant, value, defined at\n\
+             constant ALL 12 led.sasm:2:1\n\
+             constant LED 3 led.sasm:1:1\n"
+        );
+    }
+
+    #[test]
+    fn test_symbol_map_credits_jump_targets() {
+        let symbol = |name: &str| Operand::Symbol(name.to_string());
+        let tokens = vec![
+            Token::from(Constant("LOOP".to_string(), symbol("start"))),
+            Token::from(Label("start".to_string())),
+            Token::from(OutIm(1.into())),
+            Token::from(Label("next".to_string())),
+            Token::from(OutIm(2.into())),
+            Token::from(Jnc(symbol("LOOP"))),
+            Token::from(Jmp(Operand::Binary(
+                BinaryOp::Add,
+                Box::new(symbol("start")),
+                Box::new(1.into()),
+            ))),
+            Token::from(Label("__halt_1".to_string())),
+            Token::from(Jmp(symbol("__halt_1"))),
+        ];
+
+        let map = Compiler::new().symbol_map(&tokens).unwrap();
+        let labels: Vec<(&str, &[u32])> = map
+            .labels
+            .iter()
+            .map(|label| (label.name.as_str(), label.jumps.as_slice()))
+            .collect();
+        assert_eq!(labels, vec![("start", &[2][..]), ("next", &[3][..])]);
+    }
 }
@@ -0,0 +1,426 @@
+use crate::error::{Annotations, EmulatorErr};
+use crate::span::{SourceMap, Span};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Level::Error => write!(f, "error"),
+            Level::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A message about the source, ready to be rendered: an error from the
+/// parser, compiler or emulator, or a warning.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub message: String,
+    pub span: Option<Span>,
+    pub annotations: Annotations,
+}
+
+impl Diagnostic {
+    pub fn error(message: &str) -> Self {
+        Self::new(Level::Error, message)
+    }
+
+    pub fn warning(message: &str) -> Self {
+        Self::new(Level::Warning, message)
+    }
+
+    fn new(level: Level, message: &str) -> Self {
+        Self {
+            level,
+            message: message.to_string(),
+            span: None,
+            annotations: Annotations::default(),
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn with_label(mut self, span: Span, label: &str) -> Self {
+        self.annotations.labels.push((span, label.to_string()));
+        self
+    }
+
+    pub fn with_help(mut self, help: &str) -> Self {
+        self.annotations.help.push(help.to_string());
+        self
+    }
+}
+
+impl From<&EmulatorErr> for Diagnostic {
+    fn from(err: &EmulatorErr) -> Self {
+        Self {
+            level: Level::Error,
+            message: err.message(),
+            span: err.span().cloned(),
+            annotations: err.annotations().clone(),
+        }
+    }
+}
+
+/// Renders diagnostics the way rustc does, quoting the source lines they
+/// point at:
+///
+/// ```text
+/// error: label `loop` is already defined at led.sasm:1:1
+///  --> led.sasm:3:1
+///   |
+/// 1 | loop:
+///   | ----- first defined here
+/// 2 |     out 0b0011
+/// 3 | loop:
+///   | ^^^^^
+/// ```
+///
+/// Diagnostics inside a macro body are followed by a note for every
+/// invocation that led to them. Colors are off unless asked for.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Renderer {
+    color: bool,
+}
+
+/// One underlined span of a snippet.
+struct Mark<'a> {
+    span: &'a Span,
+    label: &'a str,
+    primary: bool,
+}
+
+const BOLD: &str = "1";
+const RED: &str = "1;31";
+const YELLOW: &str = "1;33";
+const GREEN: &str = "1;32";
+const BLUE: &str = "1;34";
+const CYAN: &str = "1;36";
+
+impl Renderer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether to color the output with ANSI escapes.
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic, sources: &SourceMap) -> String {
+        let level_color = match diagnostic.level {
+            Level::Error => RED,
+            Level::Warning => YELLOW,
+        };
+        let mut out = format!(
+            "{}{}\n",
+            self.paint(level_color, &diagnostic.level.to_string()),
+            self.paint(BOLD, &format!(": {}", diagnostic.message))
+        );
+
+        let labels = &diagnostic.annotations.labels;
+        let span = match &diagnostic.span {
+            Some(span) => span,
+            None => {
+                for help in &diagnostic.annotations.help {
+                    out.push_str(&format!("{}: {}\n", self.paint(CYAN, "help"), help));
+                }
+                return out;
+            }
+        };
+
+        let mut marks = vec![Mark {
+            span,
+            label: "",
+            primary: true,
+        }];
+        let mut elsewhere = Vec::new();
+        for (label_span, label) in labels {
+            let mark = Mark {
+                span: label_span,
+                label,
+                primary: false,
+            };
+            if label_span.file == span.file {
+                marks.push(mark);
+            } else {
+                elsewhere.push(mark);
+            }
+        }
+        let width = marks
+            .iter()
+            .chain(&elsewhere)
+            .map(|mark| mark.span.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+
+        self.snippet(&mut out, "-->", &mut marks, width, level_color, sources);
+        for mark in elsewhere {
+            self.snippet(&mut out, ":::", &mut [mark], width, level_color, sources);
+        }
+
+        if !diagnostic.annotations.help.is_empty() {
+            out.push_str(&format!("{}\n", self.gutter(width, "")));
+            for help in &diagnostic.annotations.help {
+                out.push_str(&format!(
+                    "{} {} {}: {}\n",
+                    " ".repeat(width),
+                    self.paint(BLUE, "="),
+                    self.paint(CYAN, "help"),
+                    help
+                ));
+            }
+        }
+
+        for expansion in span.backtrace() {
+            out.push_str(&format!(
+                "{}: in expansion of macro `{}`\n",
+                self.paint(GREEN, "note"),
+                expansion.macro_name
+            ));
+            let call_site = &expansion.call_site;
+            let width = call_site.line.to_string().len();
+            let mut marks = vec![Mark {
+                span: call_site,
+                label: "",
+                primary: true,
+            }];
+            self.snippet(&mut out, "-->", &mut marks, width, GREEN, sources);
+        }
+
+        out
+    }
+
+    /// Quotes the lines of one file that `marks` point at, in order, with
+    /// `...` between lines that aren't adjacent. The header names the
+    /// location of the first mark.
+    fn snippet(
+        &self,
+        out: &mut String,
+        arrow: &str,
+        marks: &mut [Mark],
+        width: usize,
+        primary_color: &str,
+        sources: &SourceMap,
+    ) {
+        let header = marks[0].span;
+        out.push_str(&format!(
+            "{}{} {}\n",
+            " ".repeat(width),
+            self.paint(BLUE, arrow),
+            header
+        ));
+        if sources.line(header).is_none() {
+            return;
+        }
+        out.push_str(&format!("{}\n", self.gutter(width, "")));
+
+        marks.sort_by_key(|mark| (mark.span.line, mark.span.column));
+        let mut previous: Option<usize> = None;
+        for mark in marks.iter() {
+            let line = match sources.line(mark.span) {
+                Some(line) => line.trim_end_matches('\r'),
+                None => continue,
+            };
+            if previous != Some(mark.span.line) {
+                if previous.is_some_and(|previous| mark.span.line > previous + 1) {
+                    out.push_str(&format!("{}\n", self.paint(BLUE, "...")));
+                }
+                let number = format!("{:>width$}", mark.span.line, width = width);
+                out.push_str(&format!(
+                    "{} {}\n",
+                    self.paint(BLUE, &format!("{} |", number)),
+                    line
+                ));
+            }
+            previous = Some(mark.span.line);
+
+            let (padding, width_of_mark) = underline(line, mark.span);
+            let (symbol, color) = match mark.primary {
+                true => ('^', primary_color),
+                false => ('-', BLUE),
+            };
+            let mut underline = self.paint(color, &symbol.to_string().repeat(width_of_mark));
+            if !mark.label.is_empty() {
+                underline.push_str(&format!(" {}", self.paint(color, mark.label)));
+            }
+            out.push_str(&format!(
+                "{}\n",
+                self.gutter(width, &format!("{}{}", padding, underline))
+            ));
+        }
+    }
+
+    /// A line of the left margin, followed by `text`.
+    fn gutter(&self, width: usize, text: &str) -> String {
+        let bar = format!("{} |", " ".repeat(width));
+        if text.is_empty() {
+            self.paint(BLUE, &bar)
+        } else {
+            format!("{} {}", self.paint(BLUE, &bar), text)
+        }
+    }
+
+    fn paint(&self, color: &str, text: &str) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", color, text)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+/// Renders an error for the terminal without colors. See `Renderer`.
+pub fn render(err: &EmulatorErr, sources: &SourceMap) -> String {
+    Renderer::new().render(&err.into(), sources)
+}
+
+/// The whitespace before the columns covered by `span` and how many columns
+/// it covers. Tabs before the span are kept so that the underline lines up
+/// however the terminal expands them.
+fn underline(line: &str, span: &Span) -> (String, usize) {
+    let start = span.column.saturating_sub(1);
+    let width = span.end_column.saturating_sub(span.column).max(1);
+    let padding: String = line
+        .chars()
+        .chain(std::iter::repeat(' '))
+        .take(start)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    (padding, width)
+}
+
+#[cfg(test)]
+mod diagnostics_tests {
+    use crate::diagnostics::{render, Diagnostic, Renderer};
+    use crate::error::EmulatorErr;
+    use crate::span::{Expansion, SourceMap, Span};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_render_with_source_line() {
+        let file: Arc<str> = Arc::from("led.sasm");
+        let mut sources = SourceMap::new();
+        sources.add(
+            file.clone(),
+            vec!["out 0110".to_string(), "\toub 0011\r".to_string()],
+        );
+        let err = EmulatorErr::parse("unknown mnemonic `oub`", Span::new(file, 2, 2, 5));
+
+        assert_eq!(
+            render(&err, &sources),
+            "error: unknown mnemonic `oub`\n \
+             --> led.sasm:2:2\n  \
+             |\n\
+             2 | \toub 0011\n  \
+             | \t^^^\n"
+        );
+    }
+
+    #[test]
+    fn test_render_without_span() {
+        let err = EmulatorErr::Assembly {
+            msg: "token list is empty".to_string(),
+            span: None,
+            annotations: Box::default(),
+        };
+        assert_eq!(
+            render(&err, &SourceMap::new()),
+            "error: token list is empty\n"
+        );
+    }
+
+    #[test]
+    fn test_render_macro_backtrace() {
+        let file: Arc<str> = Arc::from("led.sasm");
+        let mut sources = SourceMap::new();
+        sources.add(
+            file.clone(),
+            vec![
+                ".macro show p".to_string(),
+                "  out p".to_string(),
+                ".endm".to_string(),
+                "show 0x1f".to_string(),
+            ],
+        );
+        let mut span = Span::new(file.clone(), 2, 3, 8);
+        span.expansion = Some(Arc::new(Expansion {
+            macro_name: "show".to_string(),
+            call_site: Span::new(file, 4, 1, 10),
+        }));
+        let err = EmulatorErr::assembly("value out of range", span);
+
+        assert_eq!(
+            render(&err, &sources),
+            "error: value out of range\n \
+             --> led.sasm:2:3\n  \
+             |\n\
+             2 |   out p\n  \
+             |   ^^^^^\n\
+             note: in expansion of macro `show`\n \
+             --> led.sasm:4:1\n  \
+             |\n\
+             4 | show 0x1f\n  \
+             | ^^^^^^^^^\n"
+        );
+    }
+
+    #[test]
+    fn test_render_labels_and_help() {
+        let file: Arc<str> = Arc::from("led.sasm");
+        let other: Arc<str> = Arc::from("consts.sasm");
+        let mut sources = SourceMap::new();
+        let mut lines: Vec<String> = (1..=9).map(|_| "    out B".to_string()).collect();
+        lines[0] = "loop:".to_string();
+        lines[9 - 1] = "loop: out 1".to_string();
+        sources.add(file.clone(), lines);
+        sources.add(other.clone(), vec![".equ loop 3".to_string()]);
+        let diagnostic = Diagnostic::error("label `loop` is defined more than once")
+            .with_span(Span::new(file.clone(), 9, 1, 5))
+            .with_label(Span::new(file, 1, 1, 5), "first defined here")
+            .with_label(Span::new(other, 1, 6, 10), "also a constant")
+            .with_help("rename one of them");
+
+        assert_eq!(
+            Renderer::new().render(&diagnostic, &sources),
+            "error: label `loop` is defined more than once\n \
+             --> led.sasm:9:1\n  \
+             |\n\
+             1 | loop:\n  \
+             | ---- first defined here\n\
+             ...\n\
+             9 | loop: out 1\n  \
+             | ^^^^\n \
+             ::: consts.sasm:1:6\n  \
+             |\n\
+             1 | .equ loop 3\n  \
+             |      ---- also a constant\n  \
+             |\n  \
+             = help: rename one of them\n"
+        );
+    }
+
+    #[test]
+    fn test_render_warning_in_color() {
+        let diagnostic = Diagnostic::warning("unused label `x`").with_help("remove it");
+        assert_eq!(
+            Renderer::new()
+                .with_color(true)
+                .render(&diagnostic, &SourceMap::new()),
+            "\x1b[1;33mwarning\x1b[0m\x1b[1m: unused label `x`\x1b[0m\n\
+             \x1b[1;36mhelp\x1b[0m: remove it\n"
+        );
+    }
+}
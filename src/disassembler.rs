@@ -0,0 +1,181 @@
+use crate::op::{Opcode, OperandKinds};
+use crate::token::{Operand, Token, TokenKind};
+use std::collections::BTreeSet;
+
+/// Turns machine code back into tokens and source text that `Parser`
+/// accepts and `Compiler` assembles to the same bytes.
+#[derive(Default)]
+pub struct Disassembler;
+
+impl Disassembler {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Decodes every byte of `code` into a token. Jump targets inside the
+    /// program get a label named after their address, such as `L3`. Bytes
+    /// the assembler can't produce from an instruction, such as unassigned
+    /// opcodes, become `.db`.
+    pub fn disassemble(&self, code: &[u8]) -> Vec<Token> {
+        let targets: BTreeSet<usize> = code
+            .iter()
+            .filter_map(|&word| match Opcode::decode(word) {
+                Some((opcode, im)) if opcode.info().operands == OperandKinds::Address => {
+                    Some(usize::from(im))
+                }
+                _ => None,
+            })
+            .filter(|&target| target <= code.len())
+            .collect();
+
+        let mut tokens = Vec::new();
+        for (address, &word) in code.iter().enumerate() {
+            if targets.contains(&address) {
+                tokens.push(TokenKind::Label(label(address)).into());
+            }
+            tokens.push(Self::decode(word, &targets).into());
+        }
+        // A jump may target the address just past the last instruction.
+        if targets.contains(&code.len()) {
+            tokens.push(TokenKind::Label(label(code.len())).into());
+        }
+
+        tokens
+    }
+
+    /// The addresses of the bytes whose upper nibble isn't an opcode.
+    pub fn unassigned(&self, code: &[u8]) -> Vec<usize> {
+        code.iter()
+            .enumerate()
+            .filter(|(_, &word)| Opcode::decode(word).is_none())
+            .map(|(address, _)| address)
+            .collect()
+    }
+
+    /// Renders `code` as source text, one instruction per line, with a
+    /// comment on every byte that doesn't decode to an instruction.
+    pub fn to_source(&self, code: &[u8]) -> String {
+        let mut source = String::new();
+        let mut words = code.iter();
+
+        for token in self.disassemble(code) {
+            let line = match &token.kind {
+                TokenKind::Label(name) => {
+                    source.push_str(&format!("{}:\n", name));
+                    continue;
+                }
+                kind => format!("    {}", kind),
+            };
+            let word = *words.next().expect("one token per byte");
+            let note = match Opcode::decode(word) {
+                None => format!("unassigned opcode {:04b}", word >> 4),
+                Some((opcode, _)) if matches!(token.kind, TokenKind::Db(_)) => {
+                    format!("`{}` with a non-zero immediate", opcode.assembly(0))
+                }
+                Some(_) => String::new(),
+            };
+            if note.is_empty() {
+                source.push_str(&format!("{}\n", line));
+            } else {
+                source.push_str(&format!("{:<16}; {}\n", line, note));
+            }
+        }
+
+        source
+    }
+
+    fn decode(word: u8, targets: &BTreeSet<usize>) -> TokenKind {
+        let target = |im: u8| {
+            if targets.contains(&usize::from(im)) {
+                Operand::Symbol(label(usize::from(im)))
+            } else {
+                im.into()
+            }
+        };
+        let raw = TokenKind::Db(Operand::Literal(u32::from(word), format!("0x{:02x}", word)));
+
+        match Opcode::decode(word) {
+            Some((opcode, im)) => match opcode.info().operands {
+                OperandKinds::Address => TokenKind::from_instruction(opcode, target(im)),
+                operands if operands.has_immediate() => {
+                    TokenKind::from_instruction(opcode, im.into())
+                }
+                // The rest ignore their immediate, which the assembler
+                // always writes as zero.
+                _ if im == 0 => TokenKind::from_instruction(opcode, 0.into()),
+                _ => raw,
+            },
+            None => raw,
+        }
+    }
+}
+
+fn label(address: usize) -> String {
+    format!("L{}", address)
+}
+
+#[cfg(test)]
+mod disassembler_tests {
+    use crate::compiler::Compiler;
+    use crate::disassembler::Disassembler;
+    use crate::parser::Parser;
+    use crate::token::TokenKind::{Jnc, Label, Mov, OutB};
+    use crate::token::{Operand, Register};
+
+    fn round_trip(code: &[u8]) -> Vec<u8> {
+        let source = Disassembler::new().to_source(code);
+        let lines = source.lines().map(|line| line.to_string()).collect();
+        let tokens = Parser::new(lines).parse().unwrap();
+        Compiler::new().compile(tokens).unwrap()
+    }
+
+    #[test]
+    fn test_disassemble_tokens() {
+        let tokens = Disassembler::new().disassemble(&[0b00110001, 0b10010000, 0b11100001]);
+        let kinds: Vec<_> = tokens.into_iter().map(|token| token.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                Mov(Register::A, 1.into()),
+                Label("L1".to_string()),
+                OutB,
+                Jnc(Operand::Symbol("L1".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble_source() {
+        let code = [0b00110001, 0b11100011, 0b10000101, 0b00100001, 0b11110000];
+        assert_eq!(
+            Disassembler::new().to_source(&code),
+            "L0:\n\
+             \x20   mov A, 1\n\
+             \x20   jnc L3\n\
+             \x20   .db 0x85    ; unassigned opcode 1000\n\
+             L3:\n\
+             \x20   .db 0x21    ; `in A` with a non-zero immediate\n\
+             \x20   jmp L0\n"
+        );
+        assert_eq!(Disassembler::new().unassigned(&code), vec![2]);
+    }
+
+    #[test]
+    fn test_round_trip_every_byte() {
+        for word in 0..=u8::MAX {
+            assert_eq!(round_trip(&[word]), vec![word], "byte {:08b}", word);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_jumps() {
+        let code = [
+            0b00110001, 0b00000001, 0b11100001, 0b01000000, 0b10010000, 0b11110101,
+        ];
+        assert_eq!(round_trip(&code), code);
+
+        // Targets past the end of the program stay numeric.
+        let code = [0b11101111, 0b11110010];
+        assert_eq!(round_trip(&code), code);
+    }
+}
@@ -3,88 +3,154 @@ use crate::op::Opcode;
 use crate::port::Port;
 use crate::register::Register;
 use crate::rom::Rom;
-use num_traits::FromPrimitive;
+use crate::span::Span;
 
 pub struct CpuEmulator {
     register: Register,
     port: Port,
     rom: Rom,
+    spans: Vec<Span>,
+}
+
+/// A value before and after an instruction changed it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    pub before: u8,
+    pub after: u8,
+}
+
+/// What a single `CpuEmulator::step` did. A field is `None` when the
+/// instruction left that part of the CPU as it was.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepResult {
+    pub opcode: Opcode,
+    /// The immediate, or 0 for an instruction that doesn't take one.
+    pub im: u8,
+    pub pc_before: u8,
+    pub pc_after: u8,
+    pub register_a: Option<Change>,
+    pub register_b: Option<Change>,
+    pub carry_flag: Option<Change>,
+    pub output: Option<Change>,
+    /// Whether the program has ended: the PC ran off the end of the ROM or
+    /// the instruction was a jump to itself.
+    pub halted: bool,
+}
+
+fn change(before: u8, after: u8) -> Option<Change> {
+    (before != after).then_some(Change { before, after })
 }
 
 impl CpuEmulator {
-    pub fn with(register: Register, port: Port, rom: Rom) -> Self {
-        assert!(
-            rom.size() <= 16,
-            "Maximum memory size is 16. This program can't work."
-        );
-        Self {
+    /// Fails if the program doesn't fit in `ROM_SIZE` words.
+    pub fn try_new(register: Register, port: Port, rom: Rom) -> Result<Self, EmulatorErr> {
+        Ok(Self {
             register,
             port,
-            rom,
-        }
+            rom: Rom::try_new(rom.memory_array)?,
+            spans: Vec::new(),
+        })
     }
 
-    fn fetch(&self) -> u8 {
-        let pc = self.register.pc();
-        if self.rom.size() <= pc {
-            return 0;
-        }
+    /// Attaches the source span of every ROM word, as produced by
+    /// `Compiler::assemble`, so that runtime errors can point at the source.
+    pub fn with_spans(mut self, spans: Vec<Span>) -> Self {
+        self.spans = spans;
+        self
+    }
 
-        self.rom.read(pc)
+    fn fetch(&self) -> Result<u8, EmulatorErr> {
+        self.rom
+            .read(self.register.pc())
+            .ok_or(EmulatorErr::PcOutOfRange {
+                address: self.register.pc(),
+                annotations: Box::default(),
+            })
     }
 
     fn decode(&self, data: u8) -> Result<(Opcode, u8), EmulatorErr> {
-        let op = data >> 4;
-        let im = data & 0x0f;
-
-        if let Some(opcode) = FromPrimitive::from_u8(op) {
-            match opcode {
-                Opcode::AddA
-                | Opcode::AddB
-                | Opcode::MovA
-                | Opcode::MovB
-                | Opcode::MovA2B
-                | Opcode::MovB2A
-                | Opcode::Jmp
-                | Opcode::Jnc
-                | Opcode::OutIm => Ok((opcode, im)),
-                Opcode::InA | Opcode::InB | Opcode::OutB => Ok((opcode, 0)),
-            }
-        } else {
-            // never comes
-            Err(EmulatorErr::new("No match for opcode"))
+        match Opcode::decode(data) {
+            Some((opcode, im)) if opcode.info().operands.has_immediate() => Ok((opcode, im)),
+            Some((opcode, _)) => Ok((opcode, 0)),
+            None => Err(EmulatorErr::IllegalOpcode {
+                opcode: data >> 4,
+                address: self.register.pc(),
+                span: self.spans.get(self.register.pc() as usize).cloned(),
+                annotations: Box::default(),
+            }),
         }
     }
 
+    pub fn register(&self) -> &Register {
+        &self.register
+    }
+
+    pub fn port(&self) -> &Port {
+        &self.port
+    }
+
+    /// Runs the program until it halts, printing every write to the output
+    /// port.
     pub fn exec(&mut self) -> Result<(), EmulatorErr> {
-        loop {
-            let data = self.fetch();
-            let (opcode, im) = self.decode(data)?;
-
-            match opcode {
-                Opcode::MovA => self.mov_a(im),
-                Opcode::MovB => self.mov_b(im),
-                Opcode::AddA => self.add_a(im),
-                Opcode::AddB => self.add_b(im),
-                Opcode::MovA2B => self.mov_a2b(),
-                Opcode::MovB2A => self.mov_b2a(),
-                Opcode::Jmp => self.jmp(im),
-                Opcode::Jnc => self.jnc(im),
-                Opcode::InA => self.in_a(),
-                Opcode::InB => self.in_b(),
-                Opcode::OutB => self.out_b(),
-                Opcode::OutIm => self.out_im(im),
-            };
-
-            // To prevent infinite loop
-            if opcode != Opcode::Jmp && opcode != Opcode::Jnc {
-                self.register.incr_pc();
+        while !self.does_halt() {
+            let step = self.step()?;
+            match step.opcode {
+                Opcode::OutB => println!("Port (B) Out: {}", self.port.output()),
+                Opcode::OutIm => println!("Port Out: {}", self.port.output()),
+                _ => {}
             }
-
-            if self.does_halt() {
-                return Ok(());
+            if step.halted {
+                break;
             }
         }
+        Ok(())
+    }
+
+    /// Fetches, decodes and executes a single instruction. Fails with
+    /// `PcOutOfRange` once the PC has run off the end of the ROM.
+    pub fn step(&mut self) -> Result<StepResult, EmulatorErr> {
+        let pc = self.register.pc();
+        let register_a = self.register.register_a();
+        let register_b = self.register.register_b();
+        let carry_flag = self.register.carry_flag();
+        let output = self.port.output();
+
+        let data = self.fetch()?;
+        let (opcode, im) = self.decode(data)?;
+
+        match opcode {
+            Opcode::MovA => self.mov_a(im),
+            Opcode::MovB => self.mov_b(im),
+            Opcode::AddA => self.add_a(im),
+            Opcode::AddB => self.add_b(im),
+            Opcode::MovA2B => self.mov_a2b(),
+            Opcode::MovB2A => self.mov_b2a(),
+            Opcode::Jmp => self.jmp(im),
+            Opcode::Jnc => self.jnc(im),
+            Opcode::InA => self.in_a(),
+            Opcode::InB => self.in_b(),
+            Opcode::OutB => self.out_b(),
+            Opcode::OutIm => self.out_im(im),
+        };
+
+        // To prevent infinite loop
+        if opcode != Opcode::Jmp && opcode != Opcode::Jnc {
+            self.register.incr_pc();
+        }
+
+        Ok(StepResult {
+            opcode,
+            im,
+            pc_before: pc,
+            pc_after: self.register.pc(),
+            register_a: change(register_a, self.register.register_a()),
+            register_b: change(register_b, self.register.register_b()),
+            carry_flag: change(carry_flag, self.register.carry_flag()),
+            output: change(output, self.port.output()),
+            // A jump to itself can never be left, so it ends the program.
+            // This is what the `halt` pseudo-instruction assembles to.
+            halted: self.does_halt() || (opcode == Opcode::Jmp && im == pc),
+        })
     }
 
     fn does_halt(&self) -> bool {
@@ -115,24 +181,24 @@ impl CpuEmulator {
 
     fn add_a(&mut self, im: u8) {
         let existence = self.register.register_a();
-        let new_value = existence + im;
+        let new_value = u16::from(existence) + u16::from(im);
 
         if new_value > 0x0f {
             self.register.set_carry_flag(1);
         }
 
-        self.register.set_register_a(new_value & 0x0f);
+        self.register.set_register_a((new_value & 0x0f) as u8);
     }
 
     fn add_b(&mut self, im: u8) {
         let existence = self.register.register_b();
-        let new_value = existence + im;
+        let new_value = u16::from(existence) + u16::from(im);
 
         if new_value > 0x0f {
             self.register.set_carry_flag(1);
         }
 
-        self.register.set_register_b(new_value & 0x0f)
+        self.register.set_register_b((new_value & 0x0f) as u8)
     }
 
     fn jmp(&mut self, im: u8) {
@@ -143,6 +209,8 @@ impl CpuEmulator {
     fn jnc(&mut self, im: u8) {
         if self.register.carry_flag() == 0 {
             self.register.set_pc(im);
+        } else {
+            self.register.incr_pc();
         }
         self.register.set_carry_flag(0);
     }
@@ -163,29 +231,31 @@ impl CpuEmulator {
         let register_b = self.register.register_b();
         self.port.set_output(register_b);
         self.register.set_carry_flag(0);
-        println!("Port (B) Out: {}", self.port.output());
     }
 
     fn out_im(&mut self, im: u8) {
         self.port.set_output(im);
         self.register.set_carry_flag(0);
-        println!("Port Out: {}", self.port.output());
     }
 }
 
 #[cfg(test)]
 mod cpu_tests {
-    use crate::emulator::CpuEmulator;
+    use crate::emulator::{Change, CpuEmulator};
+    use crate::error::EmulatorErr;
+    use crate::op::Opcode;
     use crate::port::Port;
     use crate::register::Register;
     use crate::rom::Rom;
+    use crate::span::Span;
+    use std::sync::Arc;
 
     #[test]
     fn test_mov_a() {
         let rom = Rom::new(vec![0b00110001]);
         let register = Register::new();
         let port = Port::new(0b0000, 0b0000);
-        let mut emu = CpuEmulator::with(register, port, rom);
+        let mut emu = CpuEmulator::try_new(register, port, rom).unwrap();
         let proceeded = emu.exec();
 
         assert!(proceeded.is_ok());
@@ -200,7 +270,7 @@ mod cpu_tests {
         let rom = Rom::new(vec![0b01110001]);
         let register = Register::new();
         let port = Port::new(0b0000, 0b0000);
-        let mut emu = CpuEmulator::with(register, port, rom);
+        let mut emu = CpuEmulator::try_new(register, port, rom).unwrap();
         let proceeded = emu.exec();
 
         assert!(proceeded.is_ok());
@@ -216,7 +286,7 @@ mod cpu_tests {
         let mut register = Register::new();
         register.set_register_b(2);
         let port = Port::new(0b0000, 0b0000);
-        let mut emu = CpuEmulator::with(register, port, rom);
+        let mut emu = CpuEmulator::try_new(register, port, rom).unwrap();
 
         assert_eq!(emu.register.register_a(), 0);
 
@@ -234,7 +304,7 @@ mod cpu_tests {
         let mut register = Register::new();
         register.set_register_a(2);
         let port = Port::new(0b0000, 0b0000);
-        let mut emu = CpuEmulator::with(register, port, rom);
+        let mut emu = CpuEmulator::try_new(register, port, rom).unwrap();
 
         assert_eq!(emu.register.register_b(), 0);
 
@@ -252,7 +322,7 @@ mod cpu_tests {
         let mut register = Register::new();
         register.set_register_a(1);
         let port = Port::new(0b0000, 0b0000);
-        let mut emu = CpuEmulator::with(register, port, rom);
+        let mut emu = CpuEmulator::try_new(register, port, rom).unwrap();
         let proceeded = emu.exec();
 
         assert!(proceeded.is_ok());
@@ -268,7 +338,7 @@ mod cpu_tests {
         let mut register = Register::new();
         register.set_register_b(1);
         let port = Port::new(0b0000, 0b0000);
-        let mut emu = CpuEmulator::with(register, port, rom);
+        let mut emu = CpuEmulator::try_new(register, port, rom).unwrap();
         let proceeded = emu.exec();
 
         assert!(proceeded.is_ok());
@@ -283,7 +353,7 @@ mod cpu_tests {
         let rom = Rom::new(vec![0b11110010, 0b00110001, 0b01110010]);
         let register = Register::new();
         let port = Port::new(0b0000, 0b0000);
-        let mut emu = CpuEmulator::with(register, port, rom);
+        let mut emu = CpuEmulator::try_new(register, port, rom).unwrap();
         let proceeded = emu.exec();
 
         assert!(proceeded.is_ok());
@@ -292,13 +362,26 @@ mod cpu_tests {
         assert_eq!(emu.register.register_b(), 2);
     }
 
+    #[test]
+    fn test_jmp_to_self_halts() {
+        let rom = Rom::new(vec![0b00110001, 0b11110001, 0b00110010]);
+        let register = Register::new();
+        let port = Port::new(0b0000, 0b0000);
+        let mut emu = CpuEmulator::try_new(register, port, rom).unwrap();
+        let proceeded = emu.exec();
+
+        assert!(proceeded.is_ok());
+        assert_eq!(emu.register.pc(), 1);
+        assert_eq!(emu.register.register_a(), 1);
+    }
+
     #[test]
     fn test_port_in_a() {
         let rom = Rom::new(vec![0b00100000]);
         let register = Register::new();
         assert_eq!(register.register_a(), 0b0000);
         let port = Port::new(0b0001, 0b0000);
-        let mut emu = CpuEmulator::with(register, port, rom);
+        let mut emu = CpuEmulator::try_new(register, port, rom).unwrap();
         let proceeded = emu.exec();
 
         assert!(proceeded.is_ok());
@@ -312,7 +395,7 @@ mod cpu_tests {
         let register = Register::new();
         assert_eq!(register.register_b(), 0b0000);
         let port = Port::new(0b0001, 0b0000);
-        let mut emu = CpuEmulator::with(register, port, rom);
+        let mut emu = CpuEmulator::try_new(register, port, rom).unwrap();
         let proceeded = emu.exec();
 
         assert!(proceeded.is_ok());
@@ -326,7 +409,7 @@ mod cpu_tests {
         let mut register = Register::new();
         register.set_register_b(0b0001);
         let port = Port::new(0b0000, 0b0000);
-        let mut emu = CpuEmulator::with(register, port, rom);
+        let mut emu = CpuEmulator::try_new(register, port, rom).unwrap();
         let proceeded = emu.exec();
 
         assert!(proceeded.is_ok());
@@ -339,11 +422,162 @@ mod cpu_tests {
         let rom = Rom::new(vec![0b10110001]);
         let register = Register::new();
         let port = Port::new(0b0000, 0b0000);
-        let mut emu = CpuEmulator::with(register, port, rom);
+        let mut emu = CpuEmulator::try_new(register, port, rom).unwrap();
         let proceeded = emu.exec();
 
         assert!(proceeded.is_ok());
         assert_eq!(emu.port.output(), 1);
         assert_eq!(emu.register.carry_flag(), 0);
     }
+
+    #[test]
+    fn test_step() {
+        // mov A, 15; add A, 1; out 3; jmp 3
+        let rom = Rom::new(vec![0b00111111, 0b00000001, 0b10110011, 0b11110011]);
+        let register = Register::new();
+        let port = Port::new(0b0000, 0b0000);
+        let mut emu = CpuEmulator::try_new(register, port, rom).unwrap();
+
+        let step = emu.step().unwrap();
+        assert_eq!((step.opcode, step.im), (Opcode::MovA, 15));
+        assert_eq!((step.pc_before, step.pc_after), (0, 1));
+        assert_eq!(
+            step.register_a,
+            Some(Change {
+                before: 0,
+                after: 15
+            })
+        );
+        assert_eq!(step.register_b, None);
+        assert!(!step.halted);
+
+        let step = emu.step().unwrap();
+        assert_eq!(
+            step.register_a,
+            Some(Change {
+                before: 15,
+                after: 0
+            })
+        );
+        assert_eq!(
+            step.carry_flag,
+            Some(Change {
+                before: 0,
+                after: 1
+            })
+        );
+
+        let step = emu.step().unwrap();
+        assert_eq!(
+            step.output,
+            Some(Change {
+                before: 0,
+                after: 3
+            })
+        );
+        assert_eq!(
+            step.carry_flag,
+            Some(Change {
+                before: 1,
+                after: 0
+            })
+        );
+        assert_eq!(emu.port().output(), 3);
+
+        let step = emu.step().unwrap();
+        assert_eq!((step.opcode, step.pc_after), (Opcode::Jmp, 3));
+        assert_eq!(step.output, None);
+        assert!(step.halted);
+    }
+
+    #[test]
+    fn test_step_jnc_not_taken() {
+        // mov A, 15; add A, 1; jnc 0; out 1
+        let rom = Rom::new(vec![0b00111111, 0b00000001, 0b11100000, 0b10110001]);
+        let register = Register::new();
+        let port = Port::new(0b0000, 0b0000);
+        let mut emu = CpuEmulator::try_new(register, port, rom).unwrap();
+        emu.step().unwrap();
+        emu.step().unwrap();
+
+        let step = emu.step().unwrap();
+        assert_eq!(step.opcode, Opcode::Jnc);
+        assert_eq!((step.pc_before, step.pc_after), (2, 3));
+        assert_eq!(
+            step.carry_flag,
+            Some(Change {
+                before: 1,
+                after: 0
+            })
+        );
+        assert!(!step.halted);
+
+        let step = emu.step().unwrap();
+        assert_eq!(step.opcode, Opcode::OutIm);
+        assert_eq!(emu.port().output(), 1);
+        assert!(step.halted);
+    }
+
+    #[test]
+    fn test_step_past_the_end_of_the_rom() {
+        let rom = Rom::new(vec![0b10110001]);
+        let register = Register::new();
+        let port = Port::new(0b0000, 0b0000);
+        let mut emu = CpuEmulator::try_new(register, port, rom).unwrap();
+        assert!(emu.step().unwrap().halted);
+
+        let err = emu.step().unwrap_err();
+        assert!(matches!(err, EmulatorErr::PcOutOfRange { address: 1, .. }));
+        assert_eq!(emu.register.register_a(), 0);
+        assert_eq!(emu.register.pc(), 1);
+    }
+
+    #[test]
+    fn test_try_new_rejects_large_rom() {
+        let rom = Rom::new(vec![0b00000000; 17]);
+        let register = Register::new();
+        let port = Port::new(0b0000, 0b0000);
+        let err = CpuEmulator::try_new(register, port, rom).err().unwrap();
+
+        assert!(matches!(err, EmulatorErr::RomOverflow { size: 17, .. }));
+    }
+
+    #[test]
+    fn test_out_of_range_state_does_not_panic() {
+        // `add B, 15` with an 8-bit input in B, starting at the last PC.
+        let rom = Rom::new(vec![0b01100000, 0b01011111]);
+        let mut register = Register::new();
+        register.set_pc(u8::MAX);
+        let port = Port::new(0xff, 0b0000);
+        let mut emu = CpuEmulator::try_new(register, port, rom).unwrap();
+
+        assert!(emu.exec().is_ok());
+        emu.register.set_pc(0);
+        assert!(emu.exec().is_ok());
+        assert_eq!(emu.register.register_b(), 0b1110);
+        assert_eq!(emu.register.carry_flag(), 1);
+    }
+
+    #[test]
+    fn test_unknown_opcode_has_span() {
+        let rom = Rom::new(vec![0b10110001, 0b10000000]);
+        let register = Register::new();
+        let port = Port::new(0b0000, 0b0000);
+        let span = Span::new(Arc::from("test.sasm"), 2, 1, 9);
+        let mut emu = CpuEmulator::try_new(register, port, rom)
+            .unwrap()
+            .with_spans(vec![Span::default(), span.clone()]);
+        let err = emu.exec().unwrap_err();
+
+        assert_eq!(err.message(), "No match for opcode 1000");
+        assert_eq!(err.span(), Some(&span));
+        assert!(matches!(
+            err,
+            EmulatorErr::IllegalOpcode {
+                opcode: 0b1000,
+                address: 1,
+                ..
+            }
+        ));
+    }
 }
@@ -1,12 +1,217 @@
+use crate::span::Span;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
 #[derive(Debug)]
-pub struct EmulatorErr {
-    msg: String,
+pub enum EmulatorErr {
+    /// The source text isn't a valid program.
+    Parse {
+        msg: String,
+        span: Option<Span>,
+        annotations: Box<Annotations>,
+    },
+    /// The program parsed but can't be turned into machine code, such as an
+    /// undefined symbol or an immediate that doesn't fit.
+    Assembly {
+        msg: String,
+        span: Option<Span>,
+        annotations: Box<Annotations>,
+    },
+    /// The program needs more words than the ROM holds.
+    RomOverflow {
+        size: usize,
+        msg: String,
+        span: Option<Span>,
+        annotations: Box<Annotations>,
+    },
+    /// The emulator fetched a word whose upper nibble isn't an opcode.
+    IllegalOpcode {
+        opcode: u8,
+        address: u8,
+        span: Option<Span>,
+        annotations: Box<Annotations>,
+    },
+    /// The emulator was stepped after the PC ran off the end of the ROM.
+    PcOutOfRange {
+        address: u8,
+        annotations: Box<Annotations>,
+    },
+    /// A source file couldn't be read.
+    Io {
+        msg: String,
+        span: Option<Span>,
+        annotations: Box<Annotations>,
+        source: io::Error,
+    },
+}
+
+/// What a diagnostic shows besides the message and its primary span.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Annotations {
+    /// Other places that explain the error, each with a short label such as
+    /// "first defined here".
+    pub labels: Vec<(Span, String)>,
+    /// Suggestions for fixing the error.
+    pub help: Vec<String>,
 }
 
 impl EmulatorErr {
-    pub fn new(msg: &str) -> Self {
-        Self {
+    pub fn parse(msg: &str, span: Span) -> Self {
+        EmulatorErr::Parse {
+            msg: msg.to_string(),
+            span: Some(span),
+            annotations: Box::default(),
+        }
+    }
+
+    pub fn assembly(msg: &str, span: Span) -> Self {
+        EmulatorErr::Assembly {
+            msg: msg.to_string(),
+            span: Some(span),
+            annotations: Box::default(),
+        }
+    }
+
+    /// The error for a file that couldn't be read; `msg` says which file
+    /// and why it was needed. The cause is left to `source()`.
+    pub fn io(msg: &str, span: Option<Span>, source: io::Error) -> Self {
+        EmulatorErr::Io {
             msg: msg.to_string(),
+            span,
+            annotations: Box::default(),
+            source,
+        }
+    }
+
+    /// The description of the error, without its location.
+    pub fn message(&self) -> String {
+        match self {
+            EmulatorErr::Parse { msg, .. }
+            | EmulatorErr::Assembly { msg, .. }
+            | EmulatorErr::RomOverflow { msg, .. }
+            | EmulatorErr::Io { msg, .. } => msg.clone(),
+            EmulatorErr::IllegalOpcode { opcode, .. } => {
+                format!("No match for opcode {:04b}", opcode)
+            }
+            EmulatorErr::PcOutOfRange { address, .. } => {
+                format!("PC {} is past the end of the ROM", address)
+            }
+        }
+    }
+
+    /// Points at another place in the source that explains the error.
+    pub fn with_label(mut self, span: Span, label: &str) -> Self {
+        self.annotations_mut()
+            .labels
+            .push((span, label.to_string()));
+        self
+    }
+
+    /// Adds a suggestion for fixing the error.
+    pub fn with_help(mut self, help: &str) -> Self {
+        self.annotations_mut().help.push(help.to_string());
+        self
+    }
+
+    pub fn annotations(&self) -> &Annotations {
+        match self {
+            EmulatorErr::Parse { annotations, .. }
+            | EmulatorErr::Assembly { annotations, .. }
+            | EmulatorErr::RomOverflow { annotations, .. }
+            | EmulatorErr::IllegalOpcode { annotations, .. }
+            | EmulatorErr::PcOutOfRange { annotations, .. }
+            | EmulatorErr::Io { annotations, .. } => annotations,
         }
     }
+
+    fn annotations_mut(&mut self) -> &mut Annotations {
+        match self {
+            EmulatorErr::Parse { annotations, .. }
+            | EmulatorErr::Assembly { annotations, .. }
+            | EmulatorErr::RomOverflow { annotations, .. }
+            | EmulatorErr::IllegalOpcode { annotations, .. }
+            | EmulatorErr::PcOutOfRange { annotations, .. }
+            | EmulatorErr::Io { annotations, .. } => annotations,
+        }
+    }
+
+    pub fn span(&self) -> Option<&Span> {
+        match self {
+            EmulatorErr::Parse { span, .. }
+            | EmulatorErr::Assembly { span, .. }
+            | EmulatorErr::RomOverflow { span, .. }
+            | EmulatorErr::IllegalOpcode { span, .. }
+            | EmulatorErr::Io { span, .. } => span.as_ref(),
+            EmulatorErr::PcOutOfRange { .. } => None,
+        }
+    }
+}
+
+/// The location, if known, followed by the message and the macro
+/// expansions the location came from. An I/O error's cause isn't included;
+/// it is the error's `source()`.
+impl fmt::Display for EmulatorErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span() {
+            Some(span) => {
+                write!(f, "{}: {}", span, self.message())?;
+                for expansion in span.backtrace() {
+                    write!(
+                        f,
+                        " (in expansion of macro `{}` at {})",
+                        expansion.macro_name, expansion.call_site
+                    )?;
+                }
+                Ok(())
+            }
+            None => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl Error for EmulatorErr {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EmulatorErr::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod error_tests {
+    use crate::error::EmulatorErr;
+    use crate::span::Span;
+    use std::error::Error;
+    use std::io;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_display() {
+        let span = Span::new(Arc::from("a.sasm"), 2, 5, 8);
+        let err = EmulatorErr::assembly("undefined symbol `x`", span);
+        assert_eq!(err.to_string(), "a.sasm:2:5: undefined symbol `x`");
+        assert!(err.source().is_none());
+
+        let err = EmulatorErr::IllegalOpcode {
+            opcode: 0b1000,
+            address: 3,
+            span: None,
+            annotations: Box::default(),
+        };
+        assert_eq!(err.to_string(), "No match for opcode 1000");
+    }
+
+    #[test]
+    fn test_io_source() {
+        let err = EmulatorErr::io(
+            "can't read `a.sasm`",
+            None,
+            io::Error::new(io::ErrorKind::NotFound, "not found"),
+        );
+        assert_eq!(err.to_string(), "can't read `a.sasm`");
+        let source = err.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::NotFound);
+    }
 }
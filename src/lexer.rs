@@ -0,0 +1,323 @@
+use crate::error::EmulatorErr;
+use crate::span::Span;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexemeKind {
+    Ident,
+    /// A word starting with `.`, such as `.equ`.
+    Directive,
+    Number,
+    Comma,
+    Colon,
+    /// An operator or parenthesis in a constant expression.
+    Operator,
+    /// A double-quoted string, quotes included.
+    Str,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lexeme {
+    pub kind: LexemeKind,
+    pub text: String,
+    pub span: Span,
+}
+
+const TWO_CHAR_OPERATORS: [[char; 2]; 8] = [
+    ['<', '<'],
+    ['>', '>'],
+    ['<', '='],
+    ['>', '='],
+    ['=', '='],
+    ['!', '='],
+    ['&', '&'],
+    ['|', '|'],
+];
+
+/// Splits a single source line into lexemes.
+///
+/// Any run of spaces, tabs or a trailing `\r` separates lexemes, and
+/// everything after `;` or `#` is a comment. A blank or comment-only line
+/// yields no lexemes.
+pub fn lex_line(line: &str, file: &Arc<str>, number: usize) -> Result<Vec<Lexeme>, EmulatorErr> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut lexemes = Vec::new();
+    let mut pos = 0;
+
+    while pos < chars.len() {
+        let c = chars[pos];
+        let start = pos;
+
+        if c == ';' || c == '#' {
+            break;
+        }
+
+        if c.is_whitespace() {
+            pos += 1;
+            continue;
+        }
+
+        let kind = if c.is_ascii_alphabetic() || c == '_' {
+            pos = scan_word(&chars, pos);
+            LexemeKind::Ident
+        } else if c == '.' && chars.get(pos + 1).is_some_and(|c| c.is_ascii_alphabetic()) {
+            pos = scan_word(&chars, pos + 1);
+            LexemeKind::Directive
+        } else if c.is_ascii_digit() {
+            pos = scan_word(&chars, pos);
+            LexemeKind::Number
+        } else if c == ',' {
+            pos += 1;
+            LexemeKind::Comma
+        } else if c == ':' {
+            pos += 1;
+            LexemeKind::Colon
+        } else if chars
+            .get(pos + 1)
+            .is_some_and(|&next| TWO_CHAR_OPERATORS.contains(&[c, next]))
+        {
+            pos += 2;
+            LexemeKind::Operator
+        } else if "+-*/%&|^~()<>!".contains(c) {
+            pos += 1;
+            LexemeKind::Operator
+        } else if c == '"' {
+            pos += 1;
+            while pos < chars.len() && chars[pos] != '"' {
+                pos += 1;
+            }
+            if pos == chars.len() {
+                return Err(EmulatorErr::parse(
+                    "unterminated string",
+                    Span::new(file.clone(), number, start + 1, pos + 1),
+                ));
+            }
+            pos += 1;
+            LexemeKind::Str
+        } else {
+            return Err(EmulatorErr::parse(
+                &format!("unexpected character `{}`", c),
+                Span::new(file.clone(), number, start + 1, start + 2),
+            ));
+        };
+
+        lexemes.push(Lexeme {
+            kind,
+            text: chars[start..pos].iter().collect(),
+            span: Span::new(file.clone(), number, start + 1, pos + 1),
+        });
+    }
+
+    Ok(lexemes)
+}
+
+/// Parses the text of a `Number` lexeme. Accepted forms are `0b0101`,
+/// `0x5`, `0d5`, plain decimal `5` and, for compatibility with older
+/// programs, bare binary such as `0101`: a literal of only `0`s and `1`s is
+/// read as binary when it has at least four digits or a leading zero. `_`
+/// may separate digits. Returns `None` if the text is not a number, is
+/// `ambiguous` or doesn't fit a `u32`.
+pub fn parse_number(text: &str) -> Option<u32> {
+    if ambiguous(text) {
+        return None;
+    }
+    let digits = text.replace('_', "");
+    let lower = digits.to_ascii_lowercase();
+
+    let (radix, digits) = if let Some(rest) = lower.strip_prefix("0b") {
+        (2, rest)
+    } else if let Some(rest) = lower.strip_prefix("0x") {
+        (16, rest)
+    } else if let Some(rest) = lower.strip_prefix("0d") {
+        (10, rest)
+    } else if is_bare_binary(&lower) {
+        (2, lower.as_str())
+    } else {
+        (10, lower.as_str())
+    };
+
+    if digits.is_empty() {
+        return None;
+    }
+    u32::from_str_radix(digits, radix).ok()
+}
+
+/// Whether `text` is a bare literal such as `10` or `101`, which older
+/// programs read as binary but which looks decimal. These need a `0b` or
+/// `0d` prefix.
+pub fn ambiguous(text: &str) -> bool {
+    let digits = text.replace('_', "");
+    (2..4).contains(&digits.len())
+        && !digits.starts_with('0')
+        && digits.chars().all(|c| c == '0' || c == '1')
+}
+
+/// Writes `value` in decimal so that `parse_number` reads it back, adding
+/// a `0d` prefix where the digits alone would be `ambiguous` or binary.
+pub fn format_decimal(value: u32) -> String {
+    let text = value.to_string();
+    match parse_number(&text) == Some(value) {
+        true => text,
+        false => format!("0d{}", text),
+    }
+}
+
+fn is_bare_binary(digits: &str) -> bool {
+    digits.chars().all(|c| c == '0' || c == '1')
+        && (digits.len() >= 4 || (digits.len() > 1 && digits.starts_with('0')))
+}
+
+fn scan_word(chars: &[char], mut pos: usize) -> usize {
+    while pos < chars.len() && (chars[pos].is_ascii_alphanumeric() || chars[pos] == '_') {
+        pos += 1;
+    }
+    pos
+}
+
+#[cfg(test)]
+mod lexer_tests {
+    use crate::lexer::{format_decimal, lex_line, parse_number, LexemeKind};
+    use std::sync::Arc;
+
+    fn kinds_and_texts(line: &str) -> Vec<(LexemeKind, String)> {
+        lex_line(line, &Arc::from("test.sasm"), 1)
+            .unwrap()
+            .into_iter()
+            .map(|lexeme| (lexeme.kind, lexeme.text))
+            .collect()
+    }
+
+    #[test]
+    fn test_lex_instruction() {
+        assert_eq!(
+            kinds_and_texts("mov A, 0001"),
+            vec![
+                (LexemeKind::Ident, "mov".to_string()),
+                (LexemeKind::Ident, "A".to_string()),
+                (LexemeKind::Comma, ",".to_string()),
+                (LexemeKind::Number, "0001".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_whitespace_and_comments() {
+        assert_eq!(
+            kinds_and_texts("\tloop:  add\tA 0001 ; count up\r"),
+            vec![
+                (LexemeKind::Ident, "loop".to_string()),
+                (LexemeKind::Colon, ":".to_string()),
+                (LexemeKind::Ident, "add".to_string()),
+                (LexemeKind::Ident, "A".to_string()),
+                (LexemeKind::Number, "0001".to_string()),
+            ]
+        );
+        assert!(kinds_and_texts("# only a comment").is_empty());
+        assert_eq!(
+            kinds_and_texts(".equ LED"),
+            vec![
+                (LexemeKind::Directive, ".equ".to_string()),
+                (LexemeKind::Ident, "LED".to_string()),
+            ]
+        );
+        assert!(kinds_and_texts("   \r").is_empty());
+    }
+
+    #[test]
+    fn test_lex_expression() {
+        assert_eq!(
+            kinds_and_texts("(~MASK&0xf)<<1"),
+            vec![
+                (LexemeKind::Operator, "(".to_string()),
+                (LexemeKind::Operator, "~".to_string()),
+                (LexemeKind::Ident, "MASK".to_string()),
+                (LexemeKind::Operator, "&".to_string()),
+                (LexemeKind::Number, "0xf".to_string()),
+                (LexemeKind::Operator, ")".to_string()),
+                (LexemeKind::Operator, "<<".to_string()),
+                (LexemeKind::Number, "1".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_comparison() {
+        assert_eq!(
+            kinds_and_texts("BOARD>=2&&!(REV<3)||X!=Y"),
+            vec![
+                (LexemeKind::Ident, "BOARD".to_string()),
+                (LexemeKind::Operator, ">=".to_string()),
+                (LexemeKind::Number, "2".to_string()),
+                (LexemeKind::Operator, "&&".to_string()),
+                (LexemeKind::Operator, "!".to_string()),
+                (LexemeKind::Operator, "(".to_string()),
+                (LexemeKind::Ident, "REV".to_string()),
+                (LexemeKind::Operator, "<".to_string()),
+                (LexemeKind::Number, "3".to_string()),
+                (LexemeKind::Operator, ")".to_string()),
+                (LexemeKind::Operator, "||".to_string()),
+                (LexemeKind::Ident, "X".to_string()),
+                (LexemeKind::Operator, "!=".to_string()),
+                (LexemeKind::Ident, "Y".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lex_string() {
+        assert_eq!(
+            kinds_and_texts(".include \"lib/delay #1.sasm\" ; comment"),
+            vec![
+                (LexemeKind::Directive, ".include".to_string()),
+                (LexemeKind::Str, "\"lib/delay #1.sasm\"".to_string()),
+            ]
+        );
+        let err = lex_line(".include \"lib", &Arc::from("test.sasm"), 2).unwrap_err();
+        assert_eq!(err.to_string(), "test.sasm:2:10: unterminated string");
+    }
+
+    #[test]
+    fn test_lex_columns() {
+        let lexemes = lex_line("  out  B", &Arc::from("test.sasm"), 1).unwrap();
+        assert_eq!(lexemes[0].span.column, 3);
+        assert_eq!(lexemes[0].span.end_column, 6);
+        assert_eq!(lexemes[1].span.column, 8);
+    }
+
+    #[test]
+    fn test_lex_unexpected_character() {
+        let err = lex_line("mov A @", &Arc::from("test.sasm"), 4).unwrap_err();
+        assert_eq!(err.to_string(), "test.sasm:4:7: unexpected character `@`");
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("0b0101"), Some(5));
+        assert_eq!(parse_number("0B11"), Some(3));
+        assert_eq!(parse_number("0xf"), Some(15));
+        assert_eq!(parse_number("0x1F"), Some(31));
+        assert_eq!(parse_number("3"), Some(3));
+        assert_eq!(parse_number("12"), Some(12));
+        assert_eq!(parse_number("0101"), Some(5));
+        assert_eq!(parse_number("10001"), Some(17));
+        assert_eq!(parse_number("011"), Some(3));
+        assert_eq!(parse_number("0d10"), Some(10));
+        assert_eq!(parse_number("10"), None);
+        assert_eq!(parse_number("11"), None);
+        assert_eq!(parse_number("101"), None);
+        assert_eq!(parse_number("1_1"), None);
+        assert_eq!(parse_number("0b1010_0101"), Some(165));
+        assert_eq!(parse_number("0x"), None);
+        assert_eq!(parse_number("0b102"), None);
+        assert_eq!(parse_number("12ab"), None);
+    }
+
+    #[test]
+    fn test_format_decimal() {
+        for value in [0, 1, 5, 10, 11, 100, 101, 111, 1000, 255] {
+            assert_eq!(parse_number(&format_decimal(value)), Some(value));
+        }
+        assert_eq!(format_decimal(12), "12");
+        assert_eq!(format_decimal(10), "0d10");
+    }
+}
@@ -6,5 +6,10 @@ pub mod register;
 pub mod rom;
 
 pub mod compiler;
+pub mod diagnostics;
+pub mod disassembler;
+pub mod lexer;
+pub mod loader;
 pub mod parser;
+pub mod span;
 pub mod token;
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Supplies the text of source files to the parser, for the root file and
+/// for every `.include`.
+pub trait SourceLoader {
+    fn load(&self, path: &Path) -> io::Result<String>;
+}
+
+/// Reads files from disk.
+#[derive(Default)]
+pub struct FsLoader;
+
+impl SourceLoader for FsLoader {
+    fn load(&self, path: &Path) -> io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+}
+
+/// Serves files from memory, keyed by their normalized path.
+#[derive(Default)]
+pub struct MemoryLoader {
+    files: HashMap<PathBuf, String>,
+}
+
+impl MemoryLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, path: impl AsRef<Path>, text: &str) {
+        self.files
+            .insert(normalize(path.as_ref()), text.to_string());
+    }
+}
+
+impl SourceLoader for MemoryLoader {
+    fn load(&self, path: &Path) -> io::Result<String> {
+        self.files.get(&normalize(path)).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} not found", path.display()),
+            )
+        })
+    }
+}
+
+/// Removes `.` components and folds `dir/..` pairs without touching the
+/// file system, so that the same file reached by different relative paths
+/// compares equal.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                let ends_in_dir = matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                );
+                if ends_in_dir {
+                    normalized.pop();
+                } else {
+                    normalized.push("..");
+                }
+            }
+            other => normalized.push(other.as_os_str()),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod loader_tests {
+    use crate::loader::{normalize, MemoryLoader, SourceLoader};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize(Path::new("./lib/../lib/delay.sasm")),
+            PathBuf::from("lib/delay.sasm")
+        );
+        assert_eq!(
+            normalize(Path::new("../shared/./led.sasm")),
+            PathBuf::from("../shared/led.sasm")
+        );
+    }
+
+    #[test]
+    fn test_memory_loader() {
+        let mut loader = MemoryLoader::new();
+        loader.insert("lib/delay.sasm", "add A 1");
+
+        assert_eq!(
+            loader.load(Path::new("lib/./delay.sasm")).unwrap(),
+            "add A 1"
+        );
+        assert!(loader.load(Path::new("delay.sasm")).is_err());
+    }
+}
@@ -1,17 +1,255 @@
-use num_derive::FromPrimitive;
+use crate::lexer::format_decimal;
+use crate::token::Register;
+use std::fmt;
 
-#[derive(Debug, PartialEq, FromPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Opcode {
-    AddA = 0b0000,
-    AddB = 0b0101,
-    MovA = 0b0011,
-    MovB = 0b0111,
-    MovA2B = 0b0001,
-    MovB2A = 0b0100,
-    Jmp = 0b1111,
-    Jnc = 0b1110,
-    InA = 0b0010,
-    InB = 0b0110,
-    OutB = 0b1001,
-    OutIm = 0b1011,
+    AddA,
+    AddB,
+    MovA,
+    MovB,
+    MovA2B,
+    MovB2A,
+    Jmp,
+    Jnc,
+    InA,
+    InB,
+    OutB,
+    OutIm,
+}
+
+/// The operands an instruction is written with. Registers are part of the
+/// instruction itself; only an immediate is encoded in the lower nibble.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandKinds {
+    /// A fixed register, such as `in A`.
+    Register(Register),
+    /// A fixed destination and source register, such as `mov A, B`.
+    Registers(Register, Register),
+    /// A fixed register and an immediate, such as `add A, 1`.
+    RegisterImmediate(Register),
+    /// An immediate, such as `out 3`.
+    Immediate,
+    /// An immediate that is a ROM address, such as `jmp loop`.
+    Address,
+}
+
+impl OperandKinds {
+    /// Whether the lower nibble of the word is part of the instruction.
+    pub fn has_immediate(&self) -> bool {
+        matches!(
+            self,
+            OperandKinds::RegisterImmediate(_) | OperandKinds::Immediate | OperandKinds::Address
+        )
+    }
+}
+
+/// One row of the instruction set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub opcode: Opcode,
+    pub mnemonic: &'static str,
+    /// The upper nibble of the word.
+    pub bits: u8,
+    pub operands: OperandKinds,
+    /// What the instruction does, in the notation of the README.
+    pub description: &'static str,
+}
+
+/// The instruction set. The parser, compiler, emulator and disassembler
+/// all read mnemonics, operands and encodings from here. Rows sharing a
+/// mnemonic are listed in the order the parser tries them.
+pub const ISA: [Instruction; 12] = [
+    Instruction {
+        opcode: Opcode::MovA2B,
+        mnemonic: "mov",
+        bits: 0b0001,
+        operands: OperandKinds::Registers(Register::A, Register::B),
+        description: "A = B, clear carry",
+    },
+    Instruction {
+        opcode: Opcode::MovB2A,
+        mnemonic: "mov",
+        bits: 0b0100,
+        operands: OperandKinds::Registers(Register::B, Register::A),
+        description: "B = A, clear carry",
+    },
+    Instruction {
+        opcode: Opcode::MovA,
+        mnemonic: "mov",
+        bits: 0b0011,
+        operands: OperandKinds::RegisterImmediate(Register::A),
+        description: "A = im, clear carry",
+    },
+    Instruction {
+        opcode: Opcode::MovB,
+        mnemonic: "mov",
+        bits: 0b0111,
+        operands: OperandKinds::RegisterImmediate(Register::B),
+        description: "B = im, clear carry",
+    },
+    Instruction {
+        opcode: Opcode::AddA,
+        mnemonic: "add",
+        bits: 0b0000,
+        operands: OperandKinds::RegisterImmediate(Register::A),
+        description: "A = A + im, carry on overflow",
+    },
+    Instruction {
+        opcode: Opcode::AddB,
+        mnemonic: "add",
+        bits: 0b0101,
+        operands: OperandKinds::RegisterImmediate(Register::B),
+        description: "B = B + im, carry on overflow",
+    },
+    Instruction {
+        opcode: Opcode::Jmp,
+        mnemonic: "jmp",
+        bits: 0b1111,
+        operands: OperandKinds::Address,
+        description: "PC = im, clear carry",
+    },
+    Instruction {
+        opcode: Opcode::Jnc,
+        mnemonic: "jnc",
+        bits: 0b1110,
+        operands: OperandKinds::Address,
+        description: "PC = im if carry is clear, clear carry",
+    },
+    Instruction {
+        opcode: Opcode::InA,
+        mnemonic: "in",
+        bits: 0b0010,
+        operands: OperandKinds::Register(Register::A),
+        description: "A = input port, clear carry",
+    },
+    Instruction {
+        opcode: Opcode::InB,
+        mnemonic: "in",
+        bits: 0b0110,
+        operands: OperandKinds::Register(Register::B),
+        description: "B = input port, clear carry",
+    },
+    Instruction {
+        opcode: Opcode::OutB,
+        mnemonic: "out",
+        bits: 0b1001,
+        operands: OperandKinds::Register(Register::B),
+        description: "output port = B, clear carry",
+    },
+    Instruction {
+        opcode: Opcode::OutIm,
+        mnemonic: "out",
+        bits: 0b1011,
+        operands: OperandKinds::Immediate,
+        description: "output port = im, clear carry",
+    },
+];
+
+/// The distinct mnemonics of the instruction set, in table order.
+pub fn mnemonics() -> Vec<&'static str> {
+    let mut mnemonics = Vec::new();
+    for instruction in ISA.iter() {
+        if !mnemonics.contains(&instruction.mnemonic) {
+            mnemonics.push(instruction.mnemonic);
+        }
+    }
+    mnemonics
+}
+
+impl Opcode {
+    /// The row of `ISA` describing this opcode.
+    pub fn info(&self) -> &'static Instruction {
+        ISA.iter()
+            .find(|instruction| instruction.opcode == *self)
+            .expect("every opcode is in the ISA table")
+    }
+
+    /// Packs the opcode and its immediate into a ROM word. The immediate is
+    /// dropped for opcodes that don't take one.
+    pub fn encode(&self, im: u8) -> u8 {
+        let info = self.info();
+        if info.operands.has_immediate() {
+            info.bits << 4 | im & 0x0f
+        } else {
+            info.bits << 4
+        }
+    }
+
+    /// Splits a ROM word into its opcode and immediate, or returns `None`
+    /// if the upper nibble isn't an instruction.
+    pub fn decode(word: u8) -> Option<(Opcode, u8)> {
+        ISA.iter()
+            .find(|instruction| instruction.bits == word >> 4)
+            .map(|instruction| (instruction.opcode, word & 0x0f))
+    }
+
+    /// The instruction written the way the assembler accepts it.
+    pub fn assembly(&self, im: u8) -> String {
+        match self.info().operands {
+            OperandKinds::RegisterImmediate(_) => {
+                format!("{}, {}", self, format_decimal(im.into()))
+            }
+            OperandKinds::Immediate | OperandKinds::Address => {
+                format!("{} {}", self, format_decimal(im.into()))
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
+/// The mnemonic along with its register operands, such as `add A` or
+/// `mov B, A`.
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let info = self.info();
+        match info.operands {
+            OperandKinds::Register(register) | OperandKinds::RegisterImmediate(register) => {
+                write!(f, "{} {}", info.mnemonic, register)
+            }
+            OperandKinds::Registers(dst, src) => write!(f, "{} {}, {}", info.mnemonic, dst, src),
+            OperandKinds::Immediate | OperandKinds::Address => {
+                write!(f, "{}", info.mnemonic)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod op_tests {
+    use crate::op::{mnemonics, Opcode, ISA};
+
+    #[test]
+    fn test_display_opcode() {
+        assert_eq!(Opcode::MovB2A.to_string(), "mov B, A");
+        assert_eq!(Opcode::Jnc.to_string(), "jnc");
+        assert_eq!(Opcode::AddB.assembly(3), "add B, 3");
+        assert_eq!(Opcode::OutIm.assembly(9), "out 9");
+        assert_eq!(Opcode::InA.assembly(0), "in A");
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Opcode::decode(0b1110_0101), Some((Opcode::Jnc, 5)));
+        assert_eq!(Opcode::decode(0b1000_0000), None);
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(Opcode::MovB.encode(9), 0b0111_1001);
+        assert_eq!(Opcode::InB.encode(9), 0b0110_0000);
+    }
+
+    #[test]
+    fn test_isa_table() {
+        for (i, a) in ISA.iter().enumerate() {
+            assert_eq!(a.opcode.info(), a);
+            assert!(a.bits < 16, "{}", a.opcode);
+            for b in &ISA[i + 1..] {
+                assert_ne!(a.bits, b.bits, "{} and {}", a.opcode, b.opcode);
+                assert_ne!(a.opcode.to_string(), b.opcode.to_string());
+            }
+        }
+        assert_eq!(mnemonics(), vec!["mov", "add", "jmp", "jnc", "in", "out"]);
+    }
 }
@@ -1,154 +1,2377 @@
 use crate::error::EmulatorErr;
-use crate::token::{Register, Token};
+use crate::lexer::{ambiguous, format_decimal, lex_line, parse_number, Lexeme, LexemeKind};
+use crate::loader::{normalize, FsLoader, SourceLoader};
+use crate::op::{mnemonics, Instruction, OperandKinds, ISA};
+use crate::span::{Expansion, SourceMap, Span};
+use crate::token::{BinaryOp, Operand, Register, Token, TokenKind, UnaryOp, MAX_CONSTANT_DEPTH};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Instructions the TD4 doesn't have, written in terms of ones it does.
+const PSEUDO_INSTRUCTIONS: [&str; 3] = ["nop", "halt", "clc"];
+
+/// How deeply macro invocations may nest before we assume a macro is
+/// (indirectly) invoking itself.
+const MAX_EXPANSION_DEPTH: usize = 32;
+
+/// How deeply an expression may nest, counting parentheses, operators and
+/// the operands chained onto the left of a binary operator. Expressions are
+/// parsed and evaluated recursively, so this keeps the stack bounded.
+const MAX_EXPRESSION_DEPTH: usize = 64;
+
+/// The most iterations a single `.rept` may have.
+const MAX_REPEAT: i64 = 256;
+
+/// The most lines that macro and `.rept` expansions may produce between
+/// them, so that nested blocks can't multiply into more than fits in memory.
+const MAX_EXPANDED_LINES: usize = 1 << 16;
+
+const CONDITIONALS: [&str; 6] = [".if", ".ifdef", ".ifndef", ".elif", ".else", ".endif"];
 
 pub struct Parser {
+    /// Where lines are being read from, innermost last: the root file,
+    /// the files it includes, and the bodies of macros and `.rept` blocks.
+    inputs: Vec<Input>,
+    loader: Box<dyn SourceLoader>,
+    sources: SourceMap,
+    /// Where each label and constant was defined.
+    symbols: HashMap<String, Span>,
+    /// The value of every constant defined so far, for `.if` conditions.
+    constants: HashMap<String, Operand>,
+    /// Constants set through `with_define`, emitted ahead of the source.
+    predefined: Vec<Token>,
+    /// The `.if` blocks we are inside of, innermost last.
+    conditionals: Vec<Conditional>,
+    /// Every use of a symbol as an operand, checked once the whole file has
+    /// been read so that symbols can be used before their definition, with
+    /// the number of errors found before it.
+    references: Vec<(String, Span, usize)>,
+    macros: HashMap<String, Macro>,
+    /// Number of macro expansions and pseudo-instructions so far, used to
+    /// make the labels they define unique.
+    expansions: usize,
+    /// Number of lines produced by macro and `.rept` expansions so far.
+    expanded_lines: usize,
+    /// Errors found so far. A line with an error is skipped and parsing
+    /// goes on with the next one.
+    errors: Vec<EmulatorErr>,
+}
+
+/// Everything `Parser::parse_all` found: the tokens of the lines that
+/// parsed, and an error for every line that didn't, in source order.
+#[derive(Debug)]
+pub struct Parsed {
+    pub tokens: Vec<Token>,
+    pub errors: Vec<EmulatorErr>,
+}
+
+/// An open `.if`/`.ifdef`/`.ifndef` block.
+struct Conditional {
+    /// The directive that opened the block, for errors.
+    directive: Lexeme,
+    /// Whether lines are currently being assembled.
+    active: bool,
+    /// Whether a branch has been (or, inside a skipped block, can no longer
+    /// be) taken, so that later `.elif` and `.else` branches are skipped.
+    taken: bool,
+    seen_else: bool,
+}
+
+/// A source of lines.
+enum Input {
+    File(SourceFile),
+    /// Lines produced by expanding a macro or `.rept` block.
+    Lines(VecDeque<Line>),
+}
+
+/// A file being read, and how far we got.
+struct SourceFile {
+    name: Arc<str>,
+    path: PathBuf,
+    lines: Vec<String>,
     pos: usize,
-    source: Vec<String>,
+}
+
+impl SourceFile {
+    fn new(path: PathBuf, lines: Vec<String>) -> Self {
+        Self {
+            name: Arc::from(path.to_string_lossy().as_ref()),
+            path,
+            lines,
+            pos: 0,
+        }
+    }
+}
+
+/// A lexed source line.
+#[derive(Clone)]
+struct Line {
+    lexemes: Vec<Lexeme>,
+    /// Where a missing operand would have been.
+    end: Span,
+}
+
+/// A `.macro name params ... .endm` definition.
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Line>,
+}
+
+/// The operands following a mnemonic on a single line.
+struct Operands {
+    lexemes: Vec<Lexeme>,
+    pos: usize,
+    /// Where a missing operand would have been.
+    end: Span,
+}
+
+impl Operands {
+    fn peek(&self) -> Option<&Lexeme> {
+        self.lexemes.get(self.pos)
+    }
+
+    fn advance(&mut self) -> Option<Lexeme> {
+        let lexeme = self.lexemes.get(self.pos).cloned();
+        if lexeme.is_some() {
+            self.pos += 1;
+        }
+        lexeme
+    }
+
+    /// The error for an operand that should start at the current position.
+    fn missing(&self, what: &str) -> EmulatorErr {
+        let span = self
+            .peek()
+            .map_or_else(|| self.end.clone(), |lexeme| lexeme.span.clone());
+        EmulatorErr::parse(&format!("Failed to parse {}", what), span)
+    }
+
+    /// Operands may optionally be separated by a comma: `mov A, 0001`.
+    fn skip_comma(&mut self) {
+        if self
+            .peek()
+            .is_some_and(|lexeme| lexeme.kind == LexemeKind::Comma)
+        {
+            self.pos += 1;
+        }
+    }
+
+    /// Takes an operand made of a single word, such as a register.
+    fn next(&mut self, what: &str) -> Result<Lexeme, EmulatorErr> {
+        let lexeme = self
+            .peek()
+            .filter(|lexeme| {
+                lexeme.kind != LexemeKind::Comma && lexeme.kind != LexemeKind::Operator
+            })
+            .cloned()
+            .ok_or_else(|| self.missing(what))?;
+        self.pos += 1;
+        self.skip_comma();
+
+        Ok(lexeme)
+    }
+
+    /// Whether the next operands are exactly `registers`, ignoring commas.
+    fn starts_with(&self, registers: &[Register]) -> bool {
+        let mut words = self.lexemes[self.pos..]
+            .iter()
+            .filter(|lexeme| lexeme.kind != LexemeKind::Comma);
+        registers.iter().all(|register| {
+            words
+                .next()
+                .is_some_and(|word| word.text == register.to_string())
+        })
+    }
+
+    /// Fails if anything is left on the line after the last operand.
+    fn finish(&self, mnemonic: &str) -> Result<(), EmulatorErr> {
+        match self.lexemes.get(self.pos) {
+            Some(extra) => Err(EmulatorErr::parse(
+                &format!(
+                    "unexpected `{}` after `{}` instruction",
+                    extra.text, mnemonic
+                ),
+                extra.span.clone(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// The span from `start` up to the last operand consumed so far.
+    fn span_from(&self, start: &Span) -> Span {
+        self.lexemes[..self.pos]
+            .iter()
+            .rev()
+            .find(|lexeme| lexeme.kind != LexemeKind::Comma)
+            .map_or_else(|| start.clone(), |last| start.to(&last.span))
+    }
 }
 
 impl Parser {
     pub fn new(operations: Vec<String>) -> Parser {
-        let mut source = Vec::new();
-        for operation in operations {
-            let split: Vec<&str> = operation.split(' ').collect();
-            for line in split {
-                let cloned = line.to_string();
-                source.push(cloned);
+        Self::with_file("<input>", operations)
+    }
+
+    /// Like `new`, but spans in tokens and errors name `file`, and
+    /// `.include` paths are resolved relative to it.
+    pub fn with_file(file: &str, operations: Vec<String>) -> Parser {
+        let root = SourceFile::new(normalize(Path::new(file)), operations);
+        let mut sources = SourceMap::new();
+        sources.add(root.name.clone(), root.lines.clone());
+
+        Parser {
+            inputs: vec![Input::File(root)],
+            loader: Box::new(FsLoader),
+            sources,
+            symbols: HashMap::new(),
+            constants: HashMap::new(),
+            predefined: Vec::new(),
+            conditionals: Vec::new(),
+            references: Vec::new(),
+            macros: HashMap::new(),
+            expansions: 0,
+            expanded_lines: 0,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Reads the file at `path` through `loader`, which is also used for
+    /// every `.include`.
+    pub fn from_file(
+        path: impl AsRef<Path>,
+        loader: impl SourceLoader + 'static,
+    ) -> Result<Parser, EmulatorErr> {
+        let path = path.as_ref();
+        let text = loader.load(path).map_err(|err| {
+            EmulatorErr::io(&format!("can't read `{}`", path.display()), None, err)
+        })?;
+        let lines = text.lines().map(|line| line.to_string()).collect();
+
+        Ok(Self::with_file(&path.to_string_lossy(), lines).with_loader(loader))
+    }
+
+    /// Replaces the loader used for `.include`, which reads from disk by
+    /// default.
+    pub fn with_loader(mut self, loader: impl SourceLoader + 'static) -> Parser {
+        self.loader = Box::new(loader);
+        self
+    }
+
+    /// Defines the constant `name` as though the source started with
+    /// `.equ name value`, for `.if` conditions and instructions alike.
+    /// Fails if `name` isn't a valid constant name.
+    pub fn with_define(mut self, name: &str, value: u32) -> Result<Parser, EmulatorErr> {
+        Self::check_define(name)?;
+        let span = Span::new(Arc::from("<predefined>"), 0, 0, 0);
+        let value = Operand::Literal(value, format_decimal(value));
+        self.symbols.insert(name.to_string(), span.clone());
+        self.constants.insert(name.to_string(), value.clone());
+        self.predefined.retain(
+            |token| !matches!(&token.kind, TokenKind::Constant(defined, _) if defined == name),
+        );
+        self.predefined.push(Token::new(
+            TokenKind::Constant(name.to_string(), value),
+            span,
+        ));
+        Ok(self)
+    }
+
+    /// Checks that `name` could be defined with `.equ`: an identifier other
+    /// than a register name.
+    pub fn check_define(name: &str) -> Result<(), EmulatorErr> {
+        let span = Span::new(Arc::from("<predefined>"), 0, 0, 0);
+        let lexemes = lex_line(name, &span.file, 0).unwrap_or_default();
+        match lexemes.as_slice() {
+            [lexeme] if lexeme.kind == LexemeKind::Ident && lexeme.text == name => {}
+            _ if name.is_empty() => return Err(EmulatorErr::parse("missing constant name", span)),
+            _ => {
+                return Err(EmulatorErr::parse(
+                    &format!("expected a constant name, found `{}`", name),
+                    span,
+                ))
             }
         }
+        if name == "A" || name == "B" {
+            return Err(EmulatorErr::parse(
+                &format!(
+                    "`{}` is a register and can't be used as a constant name",
+                    name
+                ),
+                span,
+            ));
+        }
+        Ok(())
+    }
 
-        Parser { pos: 0, source }
+    /// The source text behind the spans this parser produces.
+    pub fn sources(&self) -> &SourceMap {
+        &self.sources
     }
 
+    /// Parses the whole source, stopping at the first error. See
+    /// `parse_all` to get every error.
     pub fn parse(&mut self) -> Result<Vec<Token>, EmulatorErr> {
-        let mut result = Vec::new();
+        let mut parsed = self.parse_all();
+        match parsed.errors.is_empty() {
+            true => Ok(parsed.tokens),
+            false => Err(parsed.errors.remove(0)),
+        }
+    }
+
+    /// Parses the whole source, recovering at the end of every line that
+    /// has an error so that a single pass reports them all.
+    pub fn parse_all(&mut self) -> Parsed {
+        let tokens = self.parse_tokens();
 
+        // Second pass: every operand symbol must be defined somewhere in the
+        // file, before or after its use. Each error goes in among those of
+        // the first pass where the use was read, to keep them in order.
+        let mut undefined = Vec::new();
+        for (name, span, found) in &self.references {
+            if !self.symbols.contains_key(name) {
+                let mut err =
+                    EmulatorErr::parse(&format!("undefined symbol `{}`", name), span.clone());
+                if let Some(suggestion) = suggest(name, self.symbols.keys().map(String::as_str)) {
+                    err = err.with_help(&format!("did you mean `{}`?", suggestion));
+                }
+                undefined.push((*found, err));
+            }
+        }
+
+        let mut undefined = undefined.into_iter().peekable();
+        let mut errors = Vec::new();
+        for (index, err) in std::mem::take(&mut self.errors).into_iter().enumerate() {
+            while let Some((_, missing)) = undefined.next_if(|(found, _)| *found <= index) {
+                errors.push(missing);
+            }
+            errors.push(err);
+        }
+        errors.extend(undefined.map(|(_, missing)| missing));
+
+        Parsed { tokens, errors }
+    }
+
+    /// Like `parse`, but doesn't require the symbols used as operands to be
+    /// defined.
+    pub(crate) fn parse_unchecked(&mut self) -> Result<Vec<Token>, EmulatorErr> {
+        let tokens = self.parse_tokens();
+        match self.errors.is_empty() {
+            true => Ok(tokens),
+            false => Err(self.errors.remove(0)),
+        }
+    }
+
+    fn parse_tokens(&mut self) -> Vec<Token> {
+        let mut result = self.predefined.clone();
+        self.parse_lines(&mut result);
+        if let Err(err) = self.close_conditionals(0) {
+            self.errors.push(err);
+        }
+        result
+    }
+
+    /// Parses the rest of the current input, recording the error of every
+    /// line that fails.
+    fn parse_lines(&mut self, result: &mut Vec<Token>) {
         loop {
-            let op = self.source.get(self.pos);
-
-            if op.is_none() {
-                break;
-            }
-
-            let op = op.unwrap();
-
-            if op == "mov" {
-                self.pos += 1;
-                let lhs = self
-                    .source
-                    .get(self.pos)
-                    .ok_or_else(|| EmulatorErr::new("Failed to parse mov left hand side value"))?;
-
-                self.pos += 1;
-                let rhs = self
-                    .source
-                    .get(self.pos)
-                    .ok_or_else(|| EmulatorErr::new("Failed to parse mov right hand side value"))?;
-
-                let token = if lhs == "B" && rhs == "A" {
-                    Token::MovBA
-                } else if lhs == "A" && rhs == "B" {
-                    Token::MovAB
-                } else {
-                    Token::Mov(
-                        Register::from(lhs.to_string()),
-                        self.from_binary_to_decimal(rhs)?,
-                    )
+            match self.next_line() {
+                Ok(Some(line)) => {
+                    if let Err(err) = self
+                        .count_expanded(&line)
+                        .and_then(|_| self.parse_line(line, result))
+                    {
+                        self.errors.push(err);
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => self.errors.push(err),
+            }
+        }
+    }
+
+    /// Lexes the next line of the current input, returning `None` at its
+    /// end even if an enclosing input has more lines.
+    fn next_line(&mut self) -> Result<Option<Line>, EmulatorErr> {
+        let file = match self.inputs.last_mut().expect("no input is being read") {
+            Input::File(file) => file,
+            Input::Lines(lines) => return Ok(lines.pop_front()),
+        };
+        let text = match file.lines.get(file.pos) {
+            Some(text) => text,
+            None => return Ok(None),
+        };
+        let line = file.pos + 1;
+        file.pos += 1;
+        let lexemes = lex_line(text, &file.name, line)?;
+        let end_column = text.trim_end().chars().count() + 1;
+        let end = Span::new(file.name.clone(), line, end_column, end_column + 1);
+
+        Ok(Some(Line { lexemes, end }))
+    }
+
+    fn parse_line(&mut self, line: Line, result: &mut Vec<Token>) -> Result<(), EmulatorErr> {
+        let Line { mut lexemes, end } = line;
+
+        let conditional = lexemes.first().is_some_and(|lexeme| {
+            lexeme.kind == LexemeKind::Directive && CONDITIONALS.contains(&lexeme.text.as_str())
+        });
+        if conditional {
+            let op = lexemes.remove(0);
+            let mut operands = Operands {
+                lexemes,
+                pos: 0,
+                end,
+            };
+            return self.parse_conditional(&op, &mut operands);
+        }
+        if self.skipping() {
+            return Ok(());
+        }
+
+        if lexemes.len() >= 2
+            && lexemes[0].kind == LexemeKind::Ident
+            && lexemes[1].kind == LexemeKind::Colon
+        {
+            let span = lexemes[0].span.to(&lexemes[1].span);
+            let name = lexemes[0].text.clone();
+            self.define("label", &lexemes[0], span.clone())?;
+            result.push(Token::new(TokenKind::Label(name), span));
+            lexemes.drain(..2);
+        }
+
+        if lexemes.is_empty() {
+            return Ok(());
+        }
+
+        let op = lexemes.remove(0);
+
+        if op.kind == LexemeKind::Ident && self.macros.contains_key(&op.text) {
+            return self.expand_macro(&op, lexemes, result);
+        }
+
+        let mut operands = Operands {
+            lexemes,
+            pos: 0,
+            end,
+        };
+
+        if op.kind == LexemeKind::Directive && op.text == ".include" {
+            return self.include(&op, &mut operands, result);
+        }
+        if op.kind == LexemeKind::Directive && op.text == ".rept" {
+            return self.repeat(&op, &mut operands, result);
+        }
+
+        match op.kind {
+            LexemeKind::Ident => {
+                let kinds = match self.parse_pseudo_instruction(&op, &mut operands)? {
+                    Some(kinds) => kinds,
+                    None => vec![self.parse_instruction(&op, &mut operands)?],
                 };
+                operands.finish(&op.text)?;
+                let span = operands.span_from(&op.span);
+                for kind in kinds {
+                    result.push(Token::new(kind, span.clone()));
+                }
+            }
+            LexemeKind::Directive => {
+                self.parse_directive(&op, &mut operands, result)?;
+                operands.finish(&op.text)?;
+            }
+            _ => {
+                return Err(EmulatorErr::parse(
+                    &format!("expected a mnemonic, found `{}`", op.text),
+                    op.span,
+                ))
+            }
+        }
+        Ok(())
+    }
 
-                result.push(token);
+    fn parse_instruction(
+        &mut self,
+        op: &Lexeme,
+        operands: &mut Operands,
+    ) -> Result<TokenKind, EmulatorErr> {
+        let mnemonic = op.text.as_str();
+        let forms: Vec<&Instruction> = ISA
+            .iter()
+            .filter(|instruction| instruction.mnemonic == mnemonic)
+            .collect();
+        if forms.is_empty() {
+            let mut msg = format!(
+                "unknown mnemonic `{}`, expected one of {}",
+                mnemonic,
+                known_mnemonics()
+                    .map(|mnemonic| format!("`{}`", mnemonic))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            if let Some(suggestion) = suggest_mnemonic(mnemonic) {
+                msg.push_str(&format!("; did you mean `{}`?", suggestion));
             }
+            return Err(EmulatorErr::parse(&msg, op.span.clone()));
+        }
 
-            if op == "add" {
-                self.pos += 1;
-                let lhs = self
-                    .source
-                    .get(self.pos)
-                    .ok_or_else(|| EmulatorErr::new("Failed to parse Add left hand side value"))?;
+        // The first form whose registers are the ones written. If none is,
+        // the first form reports what's wrong with the operands.
+        let form = forms
+            .iter()
+            .find(|form| match form.operands {
+                OperandKinds::Registers(dst, src) => operands.starts_with(&[dst, src]),
+                OperandKinds::Register(register) | OperandKinds::RegisterImmediate(register) => {
+                    operands.starts_with(&[register])
+                }
+                OperandKinds::Immediate | OperandKinds::Address => true,
+            })
+            .unwrap_or(&forms[0]);
 
-                self.pos += 1;
-                let rhs = self
-                    .source
-                    .get(self.pos)
-                    .ok_or_else(|| EmulatorErr::new("Failed to parse Add right hand side value"))?;
+        let lhs = format!("{} left hand side value", mnemonic);
+        let rhs = format!("{} right hand side value", mnemonic);
+        let im = match form.operands {
+            OperandKinds::Registers(dst, src) => {
+                Self::expect_register(operands, &lhs, dst)?;
+                Self::expect_register(operands, &rhs, src)?;
+                None
+            }
+            OperandKinds::Register(register) => {
+                Self::expect_register(operands, &format!("{} register", mnemonic), register)?;
+                None
+            }
+            OperandKinds::RegisterImmediate(register) => {
+                Self::expect_register(operands, &lhs, register)?;
+                Some(self.parse_operand(operands, &rhs)?)
+            }
+            OperandKinds::Immediate | OperandKinds::Address => {
+                Some(self.parse_operand(operands, &format!("{} im value", mnemonic))?)
+            }
+        };
+        let kind = TokenKind::from_instruction(form.opcode, im.unwrap_or_else(|| 0.into()));
 
-                let token = Token::Add(
-                    Register::from(lhs.to_string()),
-                    self.from_binary_to_decimal(rhs)?,
-                );
+        Ok(kind)
+    }
 
-                result.push(token);
+    /// Lowers a pseudo-instruction to the real instructions it stands for,
+    /// or returns `None` if `op` isn't one.
+    ///
+    /// - `nop` is `add A 0`.
+    /// - `halt` is a jump to itself, which the emulator treats as the end of
+    ///   the program.
+    /// - `clc` is a jump to the next instruction; like every instruction but
+    ///   `add`, it clears the carry flag.
+    /// - `out A` is `mov B A` followed by `out B`, so it overwrites B.
+    fn parse_pseudo_instruction(
+        &mut self,
+        op: &Lexeme,
+        operands: &mut Operands,
+    ) -> Result<Option<Vec<TokenKind>>, EmulatorErr> {
+        let kinds = match op.text.as_str() {
+            "nop" => vec![TokenKind::Add(Register::A, 0.into())],
+            "halt" => {
+                let label = self.generated_label(op);
+                vec![
+                    TokenKind::Label(label.clone()),
+                    TokenKind::Jmp(Operand::Symbol(label)),
+                ]
             }
+            "clc" => {
+                let label = self.generated_label(op);
+                vec![
+                    TokenKind::Jmp(Operand::Symbol(label.clone())),
+                    TokenKind::Label(label),
+                ]
+            }
+            "out" if operands.peek().is_some_and(|lhs| lhs.text == "A") => {
+                operands.next("out register")?;
+                vec![TokenKind::MovBA, TokenKind::OutB]
+            }
+            _ => return Ok(None),
+        };
 
-            if op == "jmp" {
-                self.pos += 1;
-                let im = self
-                    .source
-                    .get(self.pos)
-                    .ok_or_else(|| EmulatorErr::new("Failed to parse jmp im value"))?;
+        Ok(Some(kinds))
+    }
 
-                result.push(Token::Jmp(self.from_binary_to_decimal(im)?));
+    /// A label name that can't clash with one written in the source. It is
+    /// recorded as defined by `op`, so that the source can't define it
+    /// later either.
+    fn generated_label(&mut self, op: &Lexeme) -> String {
+        loop {
+            self.expansions += 1;
+            let label = format!("__{}_{}", op.text, self.expansions);
+            if !self.symbols.contains_key(&label) {
+                self.symbols.insert(label.clone(), op.span.clone());
+                return label;
             }
+        }
+    }
 
-            if op == "jnc" {
-                self.pos += 1;
-                let im = self
-                    .source
-                    .get(self.pos)
-                    .ok_or_else(|| EmulatorErr::new("Failed to parse jnc im value"))?;
+    /// Handles a directive, adding the tokens it stands for, if any, to
+    /// `result`.
+    fn parse_directive(
+        &mut self,
+        op: &Lexeme,
+        operands: &mut Operands,
+        result: &mut Vec<Token>,
+    ) -> Result<(), EmulatorErr> {
+        match op.text.as_str() {
+            // `.equ NAME value` and its alias `.define NAME value`
+            ".equ" | ".define" => {
+                let name = operands.next(&format!("{} name", op.text))?;
 
-                result.push(Token::Jnc(self.from_binary_to_decimal(im)?));
+                if name.kind != LexemeKind::Ident {
+                    return Err(EmulatorErr::parse(
+                        &format!("expected a constant name, found `{}`", name.text),
+                        name.span,
+                    ));
+                }
+                self.define("constant", &name, name.span.clone())?;
+                let value = self.parse_operand(operands, &format!("{} value", op.text))?;
+                self.constants.insert(name.text.clone(), value.clone());
+                let kind = TokenKind::Constant(name.text.clone(), value);
+                result.push(Token::new(kind, operands.span_from(&op.span)));
+                Ok(())
             }
+            ".org" => {
+                let address = self.parse_operand(operands, ".org address")?;
+                result.push(Token::new(
+                    TokenKind::Org(address),
+                    operands.span_from(&op.span),
+                ));
+                Ok(())
+            }
+            // `.db value, ...` emits one byte per value
+            ".db" => loop {
+                let start = operands.peek().map(|lexeme| lexeme.span.clone());
+                let value = self.parse_operand(operands, ".db value")?;
+                let span = operands.span_from(&start.expect("operand has a start"));
+                result.push(Token::new(TokenKind::Db(value), span));
+                if operands.peek().is_none() {
+                    break Ok(());
+                }
+            },
+            ".macro" => self.define_macro(op, operands),
+            conditional if CONDITIONALS.contains(&conditional) => Err(EmulatorErr::parse(
+                &format!("`{}` must be on a line of its own", conditional),
+                op.span.clone(),
+            )),
+            ".endm" => Err(EmulatorErr::parse(
+                "`.endm` without a matching `.macro`",
+                op.span.clone(),
+            )),
+            ".endr" => Err(EmulatorErr::parse(
+                "`.endr` without a matching `.rept`",
+                op.span.clone(),
+            )),
+            unknown => Err(EmulatorErr::parse(
+                &format!("unknown directive `{}`", unknown),
+                op.span.clone(),
+            )),
+        }
+    }
+
+    /// Whether lines are being skipped by a `.if` whose condition is false.
+    fn skipping(&self) -> bool {
+        self.conditionals
+            .last()
+            .is_some_and(|conditional| !conditional.active)
+    }
 
-            if op == "in" {
-                self.pos += 1;
-                let lhs = self.source.get(self.pos).unwrap();
-                result.push(Token::In(Register::from(lhs.to_string())));
+    /// Handles `.if`, `.ifdef`, `.ifndef`, `.elif`, `.else` and `.endif`.
+    /// Conditions are only evaluated when their branch could be taken, so a
+    /// skipped block may refer to constants that don't exist.
+    fn parse_conditional(
+        &mut self,
+        op: &Lexeme,
+        operands: &mut Operands,
+    ) -> Result<(), EmulatorErr> {
+        match op.text.as_str() {
+            ".if" | ".ifdef" | ".ifndef" => {
+                if self.skipping() {
+                    self.conditionals.push(Conditional {
+                        directive: op.clone(),
+                        active: false,
+                        taken: true,
+                        seen_else: false,
+                    });
+                    return Ok(());
+                }
+                // A condition that can't be evaluated skips every branch, so
+                // that the error isn't followed by errors from their code.
+                let condition = self.condition(op, operands);
+                let active = condition.as_ref().is_ok_and(|active| *active);
+                self.conditionals.push(Conditional {
+                    directive: op.clone(),
+                    active,
+                    taken: active || condition.is_err(),
+                    seen_else: false,
+                });
+                condition?;
             }
+            directive => {
+                let (taken, seen_else) = match self.conditionals.last() {
+                    Some(last) => (last.taken, last.seen_else),
+                    None => {
+                        return Err(EmulatorErr::parse(
+                            &format!("`{}` without a matching `.if`", directive),
+                            op.span.clone(),
+                        ))
+                    }
+                };
+                if seen_else && directive != ".endif" {
+                    return Err(EmulatorErr::parse(
+                        &format!("`{}` after `.else`", directive),
+                        op.span.clone(),
+                    ));
+                }
 
-            if op == "out" {
-                self.pos += 1;
-                let im = self
-                    .source
-                    .get(self.pos)
-                    .ok_or_else(|| EmulatorErr::new("Failed to parse out im value"))?;
+                match directive {
+                    ".elif" if taken => {
+                        self.conditionals.last_mut().unwrap().active = false;
+                        return Ok(());
+                    }
+                    ".elif" => {
+                        let condition = self.condition(op, operands);
+                        let active = condition.as_ref().is_ok_and(|active| *active);
+                        let last = self.conditionals.last_mut().unwrap();
+                        last.active = active;
+                        last.taken = active || condition.is_err();
+                        condition?;
+                    }
+                    ".else" => {
+                        let last = self.conditionals.last_mut().unwrap();
+                        last.active = !taken;
+                        last.taken = true;
+                        last.seen_else = true;
+                    }
+                    _ => {
+                        self.conditionals.pop();
+                    }
+                }
+            }
+        }
 
-                if im == "B" {
-                    result.push(Token::OutB);
-                } else {
-                    result.push(Token::OutIm(self.from_binary_to_decimal(im)?));
+        operands.finish(&op.text)
+    }
+
+    /// Evaluates the condition of a `.if`, `.elif`, `.ifdef` or `.ifndef`.
+    fn condition(&mut self, op: &Lexeme, operands: &mut Operands) -> Result<bool, EmulatorErr> {
+        match op.text.as_str() {
+            ".ifdef" | ".ifndef" => {
+                let name = operands.next(&format!("{} name", op.text))?;
+                if name.kind != LexemeKind::Ident {
+                    return Err(EmulatorErr::parse(
+                        &format!("expected a symbol name, found `{}`", name.text),
+                        name.span,
+                    ));
                 }
+                let defined = self.symbols.contains_key(&name.text);
+                Ok(defined == (op.text == ".ifdef"))
+            }
+            ".if" | ".elif" => {
+                let start = operands.peek().map(|lexeme| lexeme.span.clone());
+                let condition =
+                    self.parse_operand_now(operands, &format!("{} condition", op.text))?;
+                let span = operands.span_from(&start.unwrap_or_else(|| op.span.clone()));
+                Ok(self.value_now(&condition, &span, &mut Vec::new())? != 0)
             }
+            _ => Err(EmulatorErr::parse(
+                &format!("`{}` takes no condition", op.text),
+                op.span.clone(),
+            )),
+        }
+    }
 
-            self.pos += 1;
+    /// Evaluates an expression while the source is still being read, so it
+    /// may only use the constants defined so far. `visiting` holds the
+    /// constants being evaluated, to catch definitions that refer back to
+    /// themselves.
+    fn value_now(
+        &self,
+        operand: &Operand,
+        span: &Span,
+        visiting: &mut Vec<String>,
+    ) -> Result<i64, EmulatorErr> {
+        operand.evaluate(span, &mut |name| match self.constants.get(name) {
+            Some(_) if visiting.iter().any(|visited| visited == name) => Err(EmulatorErr::parse(
+                &format!("constant `{}` is defined in terms of itself", name),
+                span.clone(),
+            )),
+            Some(_) if visiting.len() >= MAX_CONSTANT_DEPTH => Err(EmulatorErr::parse(
+                &format!(
+                    "constant `{}` is defined through more than {} other constants",
+                    name, MAX_CONSTANT_DEPTH
+                ),
+                span.clone(),
+            )),
+            Some(value) => {
+                visiting.push(name.to_string());
+                let value = self.value_now(value, span, visiting)?;
+                visiting.pop();
+                Ok(value)
+            }
+            None if self.symbols.contains_key(name) => Err(EmulatorErr::parse(
+                &format!(
+                    "label `{}` has no address until the program is assembled",
+                    name
+                ),
+                span.clone(),
+            )),
+            None => Err(EmulatorErr::parse(
+                &format!("`{}` is not defined yet", name),
+                span.clone(),
+            )
+            .with_help("`.if` and `.rept` only see the constants defined above them")),
+        })
+    }
+
+    /// Fails if a `.if` opened after the first `depth` ones is still open,
+    /// at the end of a file or a macro body.
+    fn close_conditionals(&mut self, depth: usize) -> Result<(), EmulatorErr> {
+        match self.conditionals.get(depth) {
+            Some(open) => Err(EmulatorErr::parse(
+                &format!("`{}` without a matching `.endif`", open.directive.text),
+                open.directive.span.clone(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads `.macro name params` and the lines up to `.endm`. The body is
+    /// kept as lexed lines and only parsed when the macro is invoked.
+    /// The body is read even when the header has an error, so that its lines
+    /// aren't parsed as though they were outside the macro.
+    fn define_macro(&mut self, op: &Lexeme, operands: &mut Operands) -> Result<(), EmulatorErr> {
+        let header = self.macro_header(operands);
+        let missing_end = match &header {
+            Ok((name, _)) => format!("macro `{}` is missing its `.endm`", name.text),
+            Err(_) => "`.macro` is missing its `.endm`".to_string(),
+        };
+
+        let mut body = Vec::new();
+        loop {
+            let line = match self.next_line() {
+                Ok(Some(line)) => line,
+                Ok(None) => return Err(EmulatorErr::parse(&missing_end, op.span.clone())),
+                // Keep looking for the `.endm`.
+                Err(err) => {
+                    self.errors.push(err);
+                    continue;
+                }
+            };
+            let directive = line
+                .lexemes
+                .iter()
+                .find(|lexeme| lexeme.kind == LexemeKind::Directive);
+            match directive.map(|lexeme| lexeme.text.as_str()) {
+                Some(".endm") => break,
+                Some(".macro") => {
+                    return Err(EmulatorErr::parse(
+                        "macro definitions can't be nested",
+                        directive.unwrap().span.clone(),
+                    ))
+                }
+                _ => body.push(line),
+            }
+        }
+
+        let (name, params) = header?;
+        self.macros.insert(name.text, Macro { params, body });
+        Ok(())
+    }
+
+    /// Reads the name and parameters of a `.macro`.
+    fn macro_header(&self, operands: &mut Operands) -> Result<(Lexeme, Vec<String>), EmulatorErr> {
+        let name = operands.next("macro name")?;
+        if name.kind != LexemeKind::Ident {
+            return Err(EmulatorErr::parse(
+                &format!("expected a macro name, found `{}`", name.text),
+                name.span,
+            ));
+        }
+        if mnemonics().contains(&name.text.as_str())
+            || PSEUDO_INSTRUCTIONS.contains(&name.text.as_str())
+        {
+            return Err(EmulatorErr::parse(
+                &format!(
+                    "`{}` is an instruction and can't be used as a macro name",
+                    name.text
+                ),
+                name.span,
+            ));
+        }
+        if self.macros.contains_key(&name.text) {
+            return Err(EmulatorErr::parse(
+                &format!("macro `{}` is already defined", name.text),
+                name.span,
+            ));
+        }
+
+        let mut params = Vec::new();
+        while operands.peek().is_some() {
+            let param = operands.next("macro parameter")?;
+            if param.kind != LexemeKind::Ident
+                || param.text == "A"
+                || param.text == "B"
+                || params.contains(&param.text)
+            {
+                return Err(EmulatorErr::parse(
+                    &format!("invalid macro parameter `{}`", param.text),
+                    param.span,
+                ));
+            }
+            params.push(param.text);
+        }
+
+        Ok((name, params))
+    }
+
+    /// Handles `.include "path"` by parsing the named file in place of the
+    /// directive. The path is relative to the including file.
+    fn include(
+        &mut self,
+        op: &Lexeme,
+        operands: &mut Operands,
+        result: &mut Vec<Token>,
+    ) -> Result<(), EmulatorErr> {
+        let name = operands.next("include path")?;
+        if name.kind != LexemeKind::Str {
+            return Err(EmulatorErr::parse(
+                &format!("expected a quoted path, found `{}`", name.text),
+                name.span,
+            ));
+        }
+        operands.finish(&op.text)?;
+
+        let files: Vec<&SourceFile> = self
+            .inputs
+            .iter()
+            .filter_map(|input| match input {
+                Input::File(file) => Some(file),
+                Input::Lines(_) => None,
+            })
+            .collect();
+        let including = &files.last().expect("no file is being read").path;
+        let relative = &name.text[1..name.text.len() - 1];
+        let path = normalize(&including.parent().unwrap_or(Path::new("")).join(relative));
+
+        if let Some(first) = files.iter().position(|file| file.path == path) {
+            let cycle = files[first..]
+                .iter()
+                .map(|file| file.name.to_string())
+                .chain(std::iter::once(path.to_string_lossy().into_owned()))
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(EmulatorErr::parse(
+                &format!("include cycle: {}", cycle),
+                name.span,
+            ));
+        }
+
+        let text = self.loader.load(&path).map_err(|err| {
+            EmulatorErr::io(
+                &format!("can't include `{}`", path.display()),
+                Some(name.span.clone()),
+                err,
+            )
+        })?;
+        let file = SourceFile::new(path, text.lines().map(|line| line.to_string()).collect());
+        self.sources.add(file.name.clone(), file.lines.clone());
+        self.parse_input(Input::File(file), result)
+    }
+
+    /// Parses every line of `input` in place of the line that produced it.
+    fn parse_input(&mut self, input: Input, result: &mut Vec<Token>) -> Result<(), EmulatorErr> {
+        self.inputs.push(input);
+        let depth = self.conditionals.len();
+        self.parse_lines(result);
+        self.inputs.pop();
+        let closed = self.close_conditionals(depth);
+        self.conditionals.truncate(depth);
+        closed
+    }
+
+    /// Substitutes the arguments of a macro invocation into the macro body
+    /// and parses the resulting lines in place of the invocation. Labels
+    /// defined in the body are renamed so that every expansion gets its own.
+    fn expand_macro(
+        &mut self,
+        op: &Lexeme,
+        lexemes: Vec<Lexeme>,
+        result: &mut Vec<Token>,
+    ) -> Result<(), EmulatorErr> {
+        if op.span.backtrace().len() >= MAX_EXPANSION_DEPTH {
+            return Err(EmulatorErr::parse(
+                &format!(
+                    "macro `{}` is nested more than {} levels deep",
+                    op.text, MAX_EXPANSION_DEPTH
+                ),
+                op.span.clone(),
+            ));
+        }
+
+        let call_site = lexemes
+            .last()
+            .map_or_else(|| op.span.clone(), |last| op.span.to(&last.span));
+        let mut args: Vec<Vec<Lexeme>> = Vec::new();
+        if !lexemes.is_empty() {
+            args.push(Vec::new());
+        }
+        for lexeme in lexemes {
+            if lexeme.kind == LexemeKind::Comma {
+                args.push(Vec::new());
+            } else {
+                args.last_mut().unwrap().push(lexeme);
+            }
+        }
+        if args.iter().any(|arg| arg.is_empty()) {
+            return Err(EmulatorErr::parse(
+                &format!("empty argument in invocation of macro `{}`", op.text),
+                call_site,
+            ));
+        }
+
+        let definition = &self.macros[&op.text];
+        if args.len() != definition.params.len() {
+            return Err(EmulatorErr::parse(
+                &format!(
+                    "macro `{}` takes {} argument(s) but {} were given",
+                    op.text,
+                    definition.params.len(),
+                    args.len()
+                ),
+                call_site,
+            ));
+        }
+
+        self.expansions += 1;
+        let expansion = Arc::new(Expansion {
+            macro_name: op.text.clone(),
+            call_site,
+        });
+        let lines = substitute(
+            &definition.body,
+            &definition.params,
+            &args,
+            &format!("__{}_{}", op.text, self.expansions),
+            &|span| Span {
+                expansion: Some(expansion.clone()),
+                ..span.clone()
+            },
+        );
+
+        self.parse_input(Input::Lines(lines), result)
+    }
+
+    /// Handles `.rept COUNT[, var]` by reading the lines up to the matching
+    /// `.endr` and parsing them COUNT times, with `var` standing for the
+    /// number of the iteration, from 0. Labels defined in the body are
+    /// renamed so that every iteration gets its own.
+    fn repeat(
+        &mut self,
+        op: &Lexeme,
+        operands: &mut Operands,
+        result: &mut Vec<Token>,
+    ) -> Result<(), EmulatorErr> {
+        // As with `.macro`, the body is read even when the header has an
+        // error.
+        let header = self.repeat_header(op, operands);
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let line = match self.next_line() {
+                Ok(Some(line)) => line,
+                Ok(None) => {
+                    return Err(EmulatorErr::parse(
+                        "`.rept` is missing its `.endr`",
+                        op.span.clone(),
+                    ))
+                }
+                // Keep looking for the `.endr`.
+                Err(err) => {
+                    self.errors.push(err);
+                    continue;
+                }
+            };
+            let directive = line
+                .lexemes
+                .iter()
+                .find(|lexeme| lexeme.kind == LexemeKind::Directive);
+            match directive.map(|lexeme| lexeme.text.as_str()) {
+                Some(".endr") if depth == 0 => break,
+                Some(".endr") => depth -= 1,
+                Some(".rept") => depth += 1,
+                _ => {}
+            }
+            body.push(line);
+        }
+
+        let (count, params) = header?;
+        let mut lines = VecDeque::new();
+        for iteration in 0..count {
+            self.expansions += 1;
+            let value = Lexeme {
+                kind: LexemeKind::Number,
+                text: format_decimal(iteration as u32),
+                span: op.span.clone(),
+            };
+            lines.extend(substitute(
+                &body,
+                &params,
+                &[vec![value]],
+                &format!("__rept_{}", self.expansions),
+                &|span| span.clone(),
+            ));
+        }
+
+        self.parse_input(Input::Lines(lines), result)
+    }
+
+    /// Counts `line` against `MAX_EXPANDED_LINES` if an expansion produced
+    /// it. Lines that a nested block reads as its body aren't parsed at this
+    /// level, so they only count once they are expanded themselves. Past
+    /// the limit, the expansions being parsed are abandoned, so that the
+    /// error isn't repeated for every one of them.
+    fn count_expanded(&mut self, line: &Line) -> Result<(), EmulatorErr> {
+        if !matches!(self.inputs.last(), Some(Input::Lines(_))) {
+            return Ok(());
+        }
+        self.expanded_lines += 1;
+        if self.expanded_lines <= MAX_EXPANDED_LINES {
+            return Ok(());
+        }
+
+        for input in &mut self.inputs {
+            if let Input::Lines(lines) = input {
+                lines.clear();
+            }
+        }
+        let span = line
+            .lexemes
+            .first()
+            .map_or_else(|| line.end.clone(), |lexeme| lexeme.span.clone());
+        Err(EmulatorErr::parse(
+            &format!(
+                "macro and `.rept` expansions produce more than {} lines",
+                MAX_EXPANDED_LINES
+            ),
+            span,
+        ))
+    }
+
+    /// Reads the count and optional variable of a `.rept`.
+    fn repeat_header(
+        &mut self,
+        op: &Lexeme,
+        operands: &mut Operands,
+    ) -> Result<(i64, Vec<String>), EmulatorErr> {
+        let start = operands.peek().map(|lexeme| lexeme.span.clone());
+        let count = self.parse_operand_now(operands, ".rept count")?;
+        let count_span = operands.span_from(&start.unwrap_or_else(|| op.span.clone()));
+        let count = self.value_now(&count, &count_span, &mut Vec::new())?;
+        if !(0..=MAX_REPEAT).contains(&count) {
+            return Err(EmulatorErr::parse(
+                &format!(
+                    "`.rept` count ({}) must be between 0 and {}",
+                    count, MAX_REPEAT
+                ),
+                count_span,
+            ));
+        }
+
+        let mut params = Vec::new();
+        if operands.peek().is_some() {
+            let var = operands.next(".rept variable")?;
+            if var.kind != LexemeKind::Ident || var.text == "A" || var.text == "B" {
+                return Err(EmulatorErr::parse(
+                    &format!("invalid `.rept` variable `{}`", var.text),
+                    var.span,
+                ));
+            }
+            params.push(var.text);
+        }
+        operands.finish(&op.text)?;
+
+        Ok((count, params))
+    }
+
+    /// Records the definition of a label or constant, rejecting names that
+    /// are already taken.
+    fn define(&mut self, what: &str, name: &Lexeme, span: Span) -> Result<(), EmulatorErr> {
+        if name.text == "A" || name.text == "B" {
+            return Err(EmulatorErr::parse(
+                &format!(
+                    "`{}` is a register and can't be used as a {} name",
+                    name.text, what
+                ),
+                name.span.clone(),
+            ));
         }
+        if let Some(defined) = self.symbols.get(&name.text) {
+            return Err(EmulatorErr::parse(
+                &format!("{} `{}` is already defined at {}", what, name.text, defined),
+                span,
+            )
+            .with_label(defined.clone(), "first defined here"));
+        }
+
+        self.symbols.insert(name.text.clone(), span);
+        Ok(())
+    }
+
+    /// Takes a register operand, which must be `expected`.
+    fn expect_register(
+        operands: &mut Operands,
+        what: &str,
+        expected: Register,
+    ) -> Result<(), EmulatorErr> {
+        let lexeme = operands.next(what)?;
+        let register = Self::parse_register(&lexeme)?;
+        if register != expected {
+            return Err(EmulatorErr::parse(
+                &format!("expected register `{}`, found `{}`", expected, register),
+                lexeme.span,
+            ));
+        }
+        Ok(())
+    }
+
+    fn parse_register(lexeme: &Lexeme) -> Result<Register, EmulatorErr> {
+        Register::try_from(lexeme.text.as_str())
+            .map_err(|err| EmulatorErr::parse(&err.message(), lexeme.span.clone()))
+    }
 
-        Ok(result)
+    /// Reads an immediate: a constant expression over numeric literals and
+    /// the names of labels and constants.
+    fn parse_operand(
+        &mut self,
+        operands: &mut Operands,
+        what: &str,
+    ) -> Result<Operand, EmulatorErr> {
+        let operand = self.parse_expression(operands, what, 0, 0)?;
+        operands.skip_comma();
+        Ok(operand)
     }
 
-    fn from_binary_to_decimal(&self, text: impl Into<String>) -> Result<u8, EmulatorErr> {
-        let ret = text.into();
-        let binary_to_decimal = u8::from_str_radix(&ret, 2);
-        binary_to_decimal.map_err(|_| EmulatorErr::new(&format!("Failed to parse string: {}", ret)))
+    /// Like `parse_operand`, for an expression that `value_now` evaluates
+    /// right away. It reports its own errors for symbols that aren't
+    /// constants yet, so the uses aren't checked again at the end.
+    fn parse_operand_now(
+        &mut self,
+        operands: &mut Operands,
+        what: &str,
+    ) -> Result<Operand, EmulatorErr> {
+        let references = self.references.len();
+        let operand = self.parse_operand(operands, what);
+        self.references.truncate(references);
+        operand
+    }
+
+    /// Precedence climbing over the binary operators that bind at least as
+    /// tightly as `min_precedence`. `depth` is how deeply the expression is
+    /// nested so far.
+    fn parse_expression(
+        &mut self,
+        operands: &mut Operands,
+        what: &str,
+        min_precedence: u8,
+        mut depth: usize,
+    ) -> Result<Operand, EmulatorErr> {
+        let mut lhs = self.parse_unary(operands, what, depth)?;
+
+        while let Some(op) = operands
+            .peek()
+            .filter(|lexeme| lexeme.kind == LexemeKind::Operator)
+            .and_then(|lexeme| BinaryOp::from_symbol(&lexeme.text))
+            .filter(|op| op.precedence() >= min_precedence)
+        {
+            operands.advance();
+            depth += 1;
+            let rhs = self.parse_expression(operands, what, op.precedence() + 1, depth)?;
+            lhs = Operand::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn parse_unary(
+        &mut self,
+        operands: &mut Operands,
+        what: &str,
+        depth: usize,
+    ) -> Result<Operand, EmulatorErr> {
+        let lexeme = match operands.peek() {
+            Some(lexeme) if lexeme.kind != LexemeKind::Comma => lexeme.clone(),
+            _ => return Err(operands.missing(what)),
+        };
+        if depth > MAX_EXPRESSION_DEPTH {
+            return Err(EmulatorErr::parse(
+                &format!(
+                    "{} is nested more than {} levels deep",
+                    what, MAX_EXPRESSION_DEPTH
+                ),
+                lexeme.span,
+            ));
+        }
+
+        match lexeme.kind {
+            LexemeKind::Operator if lexeme.text == "(" => {
+                operands.advance();
+                let inner = self.parse_expression(operands, what, 0, depth + 1)?;
+                match operands.advance() {
+                    Some(close) if close.text == ")" => Ok(inner),
+                    _ => Err(EmulatorErr::parse(
+                        &format!("unclosed `(` in {}", what),
+                        lexeme.span,
+                    )),
+                }
+            }
+            LexemeKind::Operator if lexeme.text == "+" => {
+                operands.advance();
+                self.parse_unary(operands, what, depth + 1)
+            }
+            LexemeKind::Operator => match UnaryOp::from_symbol(&lexeme.text) {
+                Some(op) => {
+                    operands.advance();
+                    let operand = self.parse_unary(operands, what, depth + 1)?;
+                    Ok(Operand::Unary(op, Box::new(operand)))
+                }
+                None => Err(operands.missing(what)),
+            },
+            LexemeKind::Ident if lexeme.text == "A" || lexeme.text == "B" => {
+                Err(EmulatorErr::parse(
+                    &format!("expected an immediate, found register `{}`", lexeme.text),
+                    lexeme.span,
+                ))
+            }
+            LexemeKind::Ident => {
+                operands.advance();
+                self.references
+                    .push((lexeme.text.clone(), lexeme.span.clone(), self.errors.len()));
+                Ok(Operand::Symbol(lexeme.text))
+            }
+            LexemeKind::Number if ambiguous(&lexeme.text) => {
+                let digits = lexeme.text.replace('_', "");
+                Err(EmulatorErr::parse(
+                    &format!("`{}` could be binary or decimal", lexeme.text),
+                    lexeme.span,
+                )
+                .with_help(&format!(
+                    "write `0b{}` for binary (older programs meant {}) or `0d{}` for decimal",
+                    digits,
+                    u32::from_str_radix(&digits, 2).unwrap_or_default(),
+                    digits
+                )))
+            }
+            LexemeKind::Number => {
+                operands.advance();
+                parse_number(&lexeme.text)
+                    .map(|value| Operand::Literal(value, lexeme.text.clone()))
+                    .ok_or_else(|| {
+                        EmulatorErr::parse(
+                            &format!("Failed to parse string: {}", lexeme.text),
+                            lexeme.span,
+                        )
+                    })
+            }
+            _ => Err(operands.missing(what)),
+        }
     }
 }
 
+/// Copies `body`, replacing every parameter by its argument and renaming the
+/// labels defined in the body to start with `prefix`. `span_of` maps the
+/// spans of the copied lexemes; arguments take the span of the parameter
+/// they replace.
+fn substitute(
+    body: &[Line],
+    params: &[String],
+    args: &[Vec<Lexeme>],
+    prefix: &str,
+    span_of: &dyn Fn(&Span) -> Span,
+) -> VecDeque<Line> {
+    let labelled = |line: &Line| {
+        line.lexemes.len() >= 2
+            && line.lexemes[0].kind == LexemeKind::Ident
+            && line.lexemes[1].kind == LexemeKind::Colon
+    };
+    let locals: Vec<&str> = body
+        .iter()
+        .filter(|line| labelled(line))
+        .map(|line| line.lexemes[0].text.as_str())
+        .collect();
+
+    let mut lines = VecDeque::new();
+    for line in body {
+        // The mnemonic is never a label reference, even if a local label
+        // has the same name.
+        let mnemonic = if labelled(line) { 2 } else { 0 };
+        let mut lexemes = Vec::new();
+        for (position, lexeme) in line.lexemes.iter().enumerate() {
+            let span = span_of(&lexeme.span);
+            let param = params
+                .iter()
+                .position(|param| lexeme.kind == LexemeKind::Ident && *param == lexeme.text);
+
+            if let Some(index) = param {
+                lexemes.extend(args[index].iter().map(|arg| Lexeme {
+                    span: span.clone(),
+                    ..arg.clone()
+                }));
+            } else if lexeme.kind == LexemeKind::Ident
+                && position != mnemonic
+                && locals.contains(&lexeme.text.as_str())
+            {
+                lexemes.push(Lexeme {
+                    text: format!("{}_{}", prefix, lexeme.text),
+                    span,
+                    ..lexeme.clone()
+                });
+            } else {
+                lexemes.push(Lexeme {
+                    span,
+                    ..lexeme.clone()
+                });
+            }
+        }
+        lines.push_back(Line {
+            lexemes,
+            end: span_of(&line.end),
+        });
+    }
+    lines
+}
+
+/// Picks the known mnemonic closest to `unknown`, if any is a plausible typo.
+fn suggest_mnemonic(unknown: &str) -> Option<&'static str> {
+    suggest(&unknown.to_ascii_lowercase(), known_mnemonics())
+}
+
+/// The mnemonics of the instructions, then the pseudo-instructions.
+fn known_mnemonics() -> impl Iterator<Item = &'static str> {
+    mnemonics().into_iter().chain(PSEUDO_INSTRUCTIONS)
+}
+
+/// Picks the candidate closest to `unknown`, if any is a plausible typo.
+/// Names the assembler generates, which start with `__`, are never picked.
+fn suggest<'a>(unknown: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    candidates
+        .into_iter()
+        .filter(|candidate| !candidate.starts_with("__"))
+        .map(|candidate| (edit_distance(unknown, candidate), candidate))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
 #[cfg(test)]
 mod parser_tests {
+    use crate::error::EmulatorErr;
+    use crate::loader::MemoryLoader;
     use crate::parser::Parser;
+    use crate::token::TokenKind::{
+        self, Add, Constant, Db, Jmp, Jnc, Label, Mov, MovBA, Org, OutB, OutIm,
+    };
+    use crate::token::{BinaryOp, Operand, Register, UnaryOp};
 
     #[test]
     fn parse_simple() {
-        let code = vec![
-            "mov".to_string(),
-            "A".to_string(),
-            "0001".to_string(),
-            "add".to_string(),
-            "A".to_string(),
-            "0001".to_string(),
-        ];
+        let code = vec!["mov A 0001".to_string(), "add A 0001".to_string()];
         let mut parser = Parser::new(code);
         let result = parser.parse().unwrap();
         assert_eq!(result.len(), 2);
     }
+
+    fn lines(source: &[&str]) -> Vec<String> {
+        source.iter().map(|line| line.to_string()).collect()
+    }
+
+    fn parse_kinds(source: &[&str]) -> Vec<TokenKind> {
+        let mut parser = Parser::new(lines(source));
+        let result = parser.parse().unwrap();
+        result.into_iter().map(|token| token.kind).collect()
+    }
+
+    #[test]
+    fn parse_labels() {
+        let result = parse_kinds(&["loop: add A 0001", "jnc loop", "jmp done", "done: out B"]);
+        assert_eq!(
+            result,
+            vec![
+                Label("loop".to_string()),
+                Add(Register::A, Operand::Literal(1, "0001".to_string())),
+                Jnc(Operand::Symbol("loop".to_string())),
+                Jmp(Operand::Symbol("done".to_string())),
+                Label("done".to_string()),
+                OutB,
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_undefined_label() {
+        let mut parser = Parser::new(lines(&["out 0001", "jmp nowhere"]));
+        let err = parser.parse().unwrap_err();
+        assert_eq!(err.to_string(), "<input>:2:5: undefined symbol `nowhere`");
+    }
+
+    #[test]
+    fn parse_duplicate_label() {
+        let mut parser = Parser::new(lines(&["top: out 0001", "top: jmp top"]));
+        let err = parser.parse().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "<input>:2:1: label `top` is already defined at <input>:1:1"
+        );
+    }
+
+    #[test]
+    fn parse_annotated_source() {
+        let result = parse_kinds(&[
+            "; add one to A and show it\r",
+            "",
+            "\tmov A, 0001   # start value\r",
+            "start:",
+            "  add  A,0001",
+            "mov B, A",
+            "out\tB ; show it",
+        ]);
+        assert_eq!(
+            result,
+            vec![
+                Mov(Register::A, Operand::Literal(1, "0001".to_string())),
+                Label("start".to_string()),
+                Add(Register::A, Operand::Literal(1, "0001".to_string())),
+                MovBA,
+                OutB,
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_missing_operand() {
+        let mut parser = Parser::new(lines(&["mov A"]));
+        let err = parser.parse().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "<input>:1:6: Failed to parse mov right hand side value"
+        );
+    }
+
+    #[test]
+    fn parse_unknown_mnemonic() {
+        let mut parser = Parser::new(lines(&["out 0110", "oub 0011"]));
+        let err = parser.parse().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "<input>:2:1: unknown mnemonic `oub`, expected one of `mov`, `add`, `jmp`, `jnc`, `in`, `out`, `nop`, `halt`, `clc`; did you mean `out`?"
+        );
+    }
+
+    #[test]
+    fn parse_unknown_mnemonic_without_suggestion() {
+        let mut parser = Parser::new(lines(&["  sleep"]));
+        let err = parser.parse().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "<input>:1:3: unknown mnemonic `sleep`, expected one of `mov`, `add`, `jmp`, `jnc`, `in`, `out`, `nop`, `halt`, `clc`"
+        );
+    }
+
+    #[test]
+    fn parse_stray_operand() {
+        let mut parser = Parser::new(lines(&["0011"]));
+        let err = parser.parse().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "<input>:1:1: expected a mnemonic, found `0011`"
+        );
+    }
+
+    #[test]
+    fn parse_trailing_token() {
+        let mut parser = Parser::new(lines(&["out 0011 0110"]));
+        let err = parser.parse().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "<input>:1:10: unexpected `0110` after `out` instruction"
+        );
+    }
+
+    #[test]
+    fn parse_spans() {
+        let mut parser = Parser::with_file("led.sasm", lines(&["start:", "  mov A, 0001 ; one"]));
+        let result = parser.parse().unwrap();
+        let span = &result[1].span;
+        assert_eq!(&*span.file, "led.sasm");
+        assert_eq!(span.line, 2);
+        assert_eq!((span.column, span.end_column), (3, 14));
+        assert_eq!(parser.sources().line(span), Some("  mov A, 0001 ; one"));
+    }
+
+    #[test]
+    fn parse_numeric_literals() {
+        let result = parse_kinds(&["mov A 3", "mov B 0x3", "add A 0b11", "out 0011", "jmp 15"]);
+        assert_eq!(
+            result,
+            vec![
+                Mov(Register::A, Operand::Literal(3, "3".to_string())),
+                Mov(Register::B, Operand::Literal(3, "0x3".to_string())),
+                Add(Register::A, Operand::Literal(3, "0b11".to_string())),
+                OutIm(Operand::Literal(3, "0011".to_string())),
+                Jmp(Operand::Literal(15, "15".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_ambiguous_literals() {
+        let error = |line: &str| Parser::new(lines(&[line])).parse().unwrap_err();
+
+        let err = error("jmp 10");
+        assert_eq!(
+            err.to_string(),
+            "<input>:1:5: `10` could be binary or decimal"
+        );
+        assert_eq!(
+            err.annotations().help,
+            vec!["write `0b10` for binary (older programs meant 2) or `0d10` for decimal"]
+        );
+        assert!(error("jmp 11").annotations().help[0].contains("meant 3"));
+        assert!(error(".db 101").annotations().help[0].contains("meant 5"));
+
+        let result = parse_kinds(&["jmp 0b10", "jmp 0d11", ".rept 12, i", ".endr"]);
+        assert_eq!(
+            result[..2],
+            [
+                Jmp(Operand::Literal(2, "0b10".to_string())),
+                Jmp(Operand::Literal(11, "0d11".to_string())),
+            ]
+        );
+        let result = parse_kinds(&[
+            ".rept 12, i",
+            "  .if i > 9",
+            "    out i",
+            "  .endif",
+            ".endr",
+        ]);
+        assert_eq!(
+            result,
+            vec![
+                OutIm(Operand::Literal(10, "0d10".to_string())),
+                OutIm(Operand::Literal(11, "0d11".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_constants() {
+        let result = parse_kinds(&[
+            "mov A, LED",
+            ".equ LED 0b0011",
+            ".define LOOP, start",
+            "start: out LED",
+            "jnc LOOP",
+        ]);
+        assert_eq!(
+            result,
+            vec![
+                Mov(Register::A, Operand::Symbol("LED".to_string())),
+                Constant("LED".to_string(), Operand::Literal(3, "0b0011".to_string())),
+                Constant("LOOP".to_string(), Operand::Symbol("start".to_string())),
+                Label("start".to_string()),
+                OutIm(Operand::Symbol("LED".to_string())),
+                Jnc(Operand::Symbol("LOOP".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_constant_redefinition() {
+        let mut parser = Parser::new(lines(&[".equ LED 0001", "LED: out LED"]));
+        let err = parser.parse().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "<input>:2:1: label `LED` is already defined at <input>:1:6"
+        );
+
+        let mut parser = Parser::new(lines(&[".equ B 0001"]));
+        let err = parser.parse().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "<input>:1:6: `B` is a register and can't be used as a constant name"
+        );
+    }
+
+    #[test]
+    fn parse_long_constant_chain() {
+        let mut source = vec![".equ C0 1".to_string()];
+        for n in 1..50_000 {
+            source.push(format!(".equ C{} C{}", n, n - 1));
+        }
+        source.push(".if C49999".to_string());
+        source.push(".endif".to_string());
+        let err = Parser::new(source).parse().unwrap_err();
+        assert!(err
+            .message()
+            .ends_with("is defined through more than 64 other constants"));
+    }
+
+    #[test]
+    fn parse_expressions() {
+        let result = parse_kinds(&[
+            "start: add A, LOOP+1",
+            "mov B 15 - COUNT",
+            "out ~MASK & 0xf",
+            "jnc (start + 1) * 2",
+            ".equ LOOP 1",
+            ".equ COUNT 2",
+            ".equ MASK 3",
+        ]);
+        let symbol = |name: &str| Box::new(Operand::Symbol(name.to_string()));
+        let literal = |value: u32, text: &str| Box::new(Operand::Literal(value, text.to_string()));
+        assert_eq!(
+            result[1..5],
+            vec![
+                Add(
+                    Register::A,
+                    Operand::Binary(BinaryOp::Add, symbol("LOOP"), literal(1, "1"))
+                ),
+                Mov(
+                    Register::B,
+                    Operand::Binary(BinaryOp::Sub, literal(15, "15"), symbol("COUNT"))
+                ),
+                OutIm(Operand::Binary(
+                    BinaryOp::And,
+                    Box::new(Operand::Unary(UnaryOp::Not, symbol("MASK"))),
+                    literal(15, "0xf")
+                )),
+                Jnc(Operand::Binary(
+                    BinaryOp::Mul,
+                    Box::new(Operand::Binary(
+                        BinaryOp::Add,
+                        symbol("start"),
+                        literal(1, "1")
+                    )),
+                    literal(2, "2")
+                )),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_expression_precedence_round_trips() {
+        let result = parse_kinds(&["out 1 + 2 * 3 - (4 - 1) ^ ~0b1 | 2 << 1"]);
+        match &result[0] {
+            OutIm(operand) => {
+                assert_eq!(operand.to_string(), "1 + 2 * 3 - (4 - 1) ^ ~0b1 | 2 << 1")
+            }
+            other => panic!("unexpected token {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_unclosed_parenthesis() {
+        let mut parser = Parser::new(lines(&["out (1 + 2"]));
+        let err = parser.parse().unwrap_err();
+        assert_eq!(err.to_string(), "<input>:1:5: unclosed `(` in out im value");
+    }
+
+    #[test]
+    fn parse_deeply_nested_expression() {
+        let nested = |depth: usize| format!("out {}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(Parser::new(vec![nested(32)]).parse().is_ok());
+
+        let err = Parser::new(vec![nested(100_000)]).parse().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "<input>:1:70: out im value is nested more than 64 levels deep"
+        );
+
+        let chain = format!("out 1{}", " + 0".repeat(100_000));
+        let err = Parser::new(vec![chain]).parse().unwrap_err();
+        assert!(err
+            .message()
+            .ends_with("is nested more than 64 levels deep"));
+        let negated = format!("out {}1", "-".repeat(100_000));
+        assert!(Parser::new(vec![negated]).parse().is_err());
+    }
+
+    #[test]
+    fn parse_macro_expansion() {
+        let result = parse_kinds(&[
+            ".macro show value, reg",
+            "  mov reg, value",
+            "  out value + 1",
+            ".endm",
+            "show 0b0011, A",
+            "show LED, B",
+            ".equ LED 2",
+        ]);
+        assert_eq!(
+            result,
+            vec![
+                Mov(Register::A, Operand::Literal(3, "0b0011".to_string())),
+                OutIm(Operand::Binary(
+                    BinaryOp::Add,
+                    Box::new(Operand::Literal(3, "0b0011".to_string())),
+                    Box::new(Operand::Literal(1, "1".to_string()))
+                )),
+                Mov(Register::B, Operand::Symbol("LED".to_string())),
+                OutIm(Operand::Binary(
+                    BinaryOp::Add,
+                    Box::new(Operand::Symbol("LED".to_string())),
+                    Box::new(Operand::Literal(1, "1".to_string()))
+                )),
+                Constant("LED".to_string(), Operand::Literal(2, "2".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_macro_local_labels() {
+        let result = parse_kinds(&[
+            ".macro count_up",
+            "again: add A 1",
+            "  jnc again",
+            ".endm",
+            "count_up",
+            "count_up",
+        ]);
+        assert_eq!(
+            result,
+            vec![
+                Label("__count_up_1_again".to_string()),
+                Add(Register::A, Operand::Literal(1, "1".to_string())),
+                Jnc(Operand::Symbol("__count_up_1_again".to_string())),
+                Label("__count_up_2_again".to_string()),
+                Add(Register::A, Operand::Literal(1, "1".to_string())),
+                Jnc(Operand::Symbol("__count_up_2_again".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_local_label_named_like_a_mnemonic() {
+        let expected = |prefix: &str| {
+            vec![
+                Label(format!("{}_out", prefix)),
+                OutIm(Operand::Literal(1, "1".to_string())),
+                Jmp(Operand::Symbol(format!("{}_out", prefix))),
+            ]
+        };
+        let result = parse_kinds(&[".macro m", "out: out 1", "  jmp out", ".endm", "m"]);
+        assert_eq!(result, expected("__m_1"));
+        let result = parse_kinds(&[".rept 1", "out: out 1", "  jmp out", ".endr"]);
+        assert_eq!(result, expected("__rept_1"));
+    }
+
+    #[test]
+    fn parse_macro_error_backtrace() {
+        let mut parser = Parser::new(lines(&[
+            ".macro inner p",
+            "  oub p",
+            ".endm",
+            ".macro outer p",
+            "  inner p",
+            ".endm",
+            "outer 1",
+        ]));
+        let err = parser.parse().unwrap_err();
+        assert_eq!(err.span().unwrap().line, 2);
+        assert_eq!(
+            err.to_string(),
+            "<input>:2:3: unknown mnemonic `oub`, expected one of `mov`, `add`, `jmp`, `jnc`, `in`, `out`, `nop`, `halt`, `clc`; did you mean `out`? \
+             (in expansion of macro `inner` at <input>:5:3) \
+             (in expansion of macro `outer` at <input>:7:1)"
+        );
+    }
+
+    #[test]
+    fn parse_macro_argument_count() {
+        let mut parser = Parser::new(lines(&[".macro show a, b", "out a", ".endm", "show 1"]));
+        let err = parser.parse().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "<input>:4:1: macro `show` takes 2 argument(s) but 1 were given"
+        );
+    }
+
+    #[test]
+    fn parse_recursive_macro() {
+        let mut parser = Parser::new(lines(&[".macro loop", "loop", ".endm", "loop"]));
+        let err = parser.parse().unwrap_err();
+        assert!(err
+            .message()
+            .starts_with("macro `loop` is nested more than 32 levels deep"));
+    }
+
+    #[test]
+    fn parse_unterminated_macro() {
+        let mut parser = Parser::new(lines(&[".macro show", "out 1"]));
+        let err = parser.parse().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "<input>:1:1: macro `show` is missing its `.endm`"
+        );
+    }
+
+    fn include_loader() -> MemoryLoader {
+        let mut loader = MemoryLoader::new();
+        loader.insert(
+            "src/main.sasm",
+            ".include \"lib/led.sasm\"\nstart: out LED\njmp start",
+        );
+        loader.insert(
+            "src/lib/led.sasm",
+            ".include \"../consts.sasm\"\n.equ LED, ON",
+        );
+        loader.insert("src/consts.sasm", ".equ ON, 0b0011");
+        loader
+    }
+
+    #[test]
+    fn parse_include() {
+        let mut parser = Parser::from_file("src/main.sasm", include_loader()).unwrap();
+        let result = parser.parse().unwrap();
+        let kinds: Vec<TokenKind> = result.iter().map(|token| token.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                Constant("ON".to_string(), Operand::Literal(3, "0b0011".to_string())),
+                Constant("LED".to_string(), Operand::Symbol("ON".to_string())),
+                Label("start".to_string()),
+                OutIm(Operand::Symbol("LED".to_string())),
+                Jmp(Operand::Symbol("start".to_string())),
+            ]
+        );
+        assert_eq!(result[0].span.to_string(), "src/consts.sasm:1:1");
+        assert_eq!(result[1].span.to_string(), "src/lib/led.sasm:2:1");
+        assert_eq!(result[2].span.to_string(), "src/main.sasm:2:1");
+        assert_eq!(parser.sources().line(&result[1].span), Some(".equ LED, ON"));
+    }
+
+    #[test]
+    fn parse_include_cycle() {
+        let mut loader = include_loader();
+        loader.insert("src/consts.sasm", ".include \"main.sasm\"");
+        let mut parser = Parser::from_file("src/main.sasm", loader).unwrap();
+        let err = parser.parse().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "src/consts.sasm:1:10: include cycle: \
+             src/main.sasm -> src/lib/led.sasm -> src/consts.sasm -> src/main.sasm"
+        );
+    }
+
+    #[test]
+    fn parse_include_missing_file() {
+        let mut parser = Parser::new(lines(&["out 1", ".include \"nowhere.sasm\""]))
+            .with_loader(MemoryLoader::new());
+        let err = parser.parse().unwrap_err();
+        assert_eq!(err.span().unwrap().line, 2);
+        assert_eq!(err.message(), "can't include `nowhere.sasm`");
+
+        let err = Parser::from_file("missing.sasm", MemoryLoader::new())
+            .err()
+            .unwrap();
+        assert_eq!(err.message(), "can't read `missing.sasm`");
+        assert!(matches!(err, EmulatorErr::Io { .. }));
+    }
+
+    #[test]
+    fn parse_include_needs_quoted_path() {
+        let mut parser = Parser::new(lines(&[".include consts.sasm"]));
+        let err = parser.parse().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "<input>:1:10: expected a quoted path, found `consts`"
+        );
+    }
+
+    #[test]
+    fn parse_org_and_db() {
+        let mut parser = Parser::new(lines(&[
+            ".equ LOW 0b0110",
+            ".org 0x4",
+            ".db 0b1000_0101, LOW | 1",
+        ]));
+        let result = parser.parse().unwrap();
+        assert_eq!(result[1].kind, Org(Operand::Literal(4, "0x4".to_string())));
+        assert_eq!(
+            result[2].kind,
+            Db(Operand::Literal(0b1000_0101, "0b1000_0101".to_string()))
+        );
+        assert_eq!(
+            result[3].kind,
+            Db(Operand::Binary(
+                BinaryOp::Or,
+                Box::new(Operand::Symbol("LOW".to_string())),
+                Box::new(Operand::Literal(1, "1".to_string())),
+            ))
+        );
+        assert_eq!((result[3].span.column, result[3].span.end_column), (18, 25));
+
+        let err = Parser::new(lines(&[".db"])).parse().unwrap_err();
+        assert_eq!(err.to_string(), "<input>:1:4: Failed to parse .db value");
+    }
+
+    #[test]
+    fn parse_pseudo_instructions() {
+        let result = parse_kinds(&["nop", "clc", "out A", "halt"]);
+        assert_eq!(
+            result,
+            vec![
+                Add(Register::A, Operand::Literal(0, "0".to_string())),
+                Jmp(Operand::Symbol("__clc_1".to_string())),
+                Label("__clc_1".to_string()),
+                MovBA,
+                OutB,
+                Label("__halt_2".to_string()),
+                Jmp(Operand::Symbol("__halt_2".to_string())),
+            ]
+        );
+
+        let result = parse_kinds(&["__halt_1:", "halt"]);
+        assert_eq!(result[2], Jmp(Operand::Symbol("__halt_2".to_string())));
+        let err = Parser::new(lines(&["halt", "__halt_1: nop"]))
+            .parse()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "<input>:2:1: label `__halt_1` is already defined at <input>:1:1"
+        );
+
+        let err = Parser::new(lines(&["hlt"])).parse().unwrap_err();
+        assert!(err.message().ends_with("did you mean `halt`?"));
+        let err = Parser::new(lines(&["nop 1"])).parse().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "<input>:1:5: unexpected `1` after `nop` instruction"
+        );
+    }
+
+    #[test]
+    fn parse_conditionals() {
+        let source = [
+            ".equ REV 2",
+            ".if BOARD == 1",
+            "  out 0b0001",
+            ".elif BOARD == 2 && REV >= 2",
+            "  out 0b0010",
+            "  .ifdef DEBUG",
+            "    out B",
+            "  .endif",
+            ".else",
+            "  out 0b1111",
+            ".endif",
+            ".ifndef DEBUG",
+            "  nop",
+            ".endif",
+        ];
+        let tokens = |parser: Parser| -> Vec<TokenKind> {
+            let mut parser = parser;
+            let result = parser.parse().unwrap();
+            result.into_iter().map(|token| token.kind).collect()
+        };
+
+        let result = tokens(Parser::new(lines(&source)).with_define("BOARD", 2).unwrap());
+        assert_eq!(
+            &result[2..],
+            &[
+                OutIm(Operand::Literal(2, "0b0010".to_string())),
+                Add(Register::A, Operand::Literal(0, "0".to_string())),
+            ]
+        );
+        assert_eq!(
+            result[0],
+            Constant("BOARD".to_string(), Operand::Literal(2, "2".to_string()))
+        );
+
+        let result = tokens(
+            Parser::new(lines(&source))
+                .with_define("BOARD", 2)
+                .and_then(|parser| parser.with_define("DEBUG", 1))
+                .unwrap(),
+        );
+        assert_eq!(
+            &result[3..],
+            &[OutIm(Operand::Literal(2, "0b0010".to_string())), OutB]
+        );
+
+        let result = tokens(Parser::new(lines(&source)).with_define("BOARD", 3).unwrap());
+        assert_eq!(result[2], OutIm(Operand::Literal(15, "0b1111".to_string())));
+
+        let error = |name| {
+            Parser::new(Vec::new())
+                .with_define(name, 1)
+                .err()
+                .unwrap()
+                .message()
+        };
+        assert_eq!(error(""), "missing constant name");
+        assert_eq!(error("1x"), "expected a constant name, found `1x`");
+        assert_eq!(error("LED ON"), "expected a constant name, found `LED ON`");
+        assert_eq!(
+            error("A"),
+            "`A` is a register and can't be used as a constant name"
+        );
+    }
+
+    #[test]
+    fn parse_conditional_skips_undefined_symbols() {
+        let result = parse_kinds(&[
+            ".if 1",
+            "  out B",
+            ".elif UNDEFINED",
+            ".endif",
+            ".if 0",
+            "  .if UNDEFINED",
+            "    bogus line",
+            "  .endif",
+            ".endif",
+        ]);
+        assert_eq!(result, vec![OutB]);
+    }
+
+    #[test]
+    fn parse_conditional_errors() {
+        let error = |source: &[&str]| Parser::new(lines(source)).parse().unwrap_err().to_string();
+
+        assert_eq!(
+            error(&[".if 1", "out B"]),
+            "<input>:1:1: `.if` without a matching `.endif`"
+        );
+        assert_eq!(
+            error(&["out B", ".endif"]),
+            "<input>:2:1: `.endif` without a matching `.if`"
+        );
+        assert_eq!(
+            error(&[".if 1", ".else", ".elif 1", ".endif"]),
+            "<input>:3:1: `.elif` after `.else`"
+        );
+        assert_eq!(
+            error(&[".if LATER", ".endif", ".equ LATER 1"]),
+            "<input>:1:5: `LATER` is not defined yet"
+        );
+        assert_eq!(
+            error(&["start: out B", ".if start", ".endif"]),
+            "<input>:2:5: label `start` has no address until the program is assembled"
+        );
+        assert_eq!(
+            error(&["start: .if 1", ".endif"]),
+            "<input>:1:8: `.if` must be on a line of its own"
+        );
+    }
+
+    #[test]
+    fn parse_rept() {
+        let result = parse_kinds(&[
+            ".equ LEDS 3",
+            ".rept LEDS, i",
+            "  out 1 << i",
+            ".endr",
+            ".rept 2",
+            "wait: jnc wait",
+            ".endr",
+        ]);
+        let shifted = |i: u32| {
+            OutIm(Operand::Binary(
+                BinaryOp::Shl,
+                Box::new(Operand::Literal(1, "1".to_string())),
+                Box::new(Operand::Literal(i, i.to_string())),
+            ))
+        };
+        assert_eq!(
+            &result[1..],
+            &[
+                shifted(0),
+                shifted(1),
+                shifted(2),
+                Label("__rept_4_wait".to_string()),
+                Jnc(Operand::Symbol("__rept_4_wait".to_string())),
+                Label("__rept_5_wait".to_string()),
+                Jnc(Operand::Symbol("__rept_5_wait".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_nested_rept() {
+        let result = parse_kinds(&[
+            ".rept 2, row",
+            "  .rept 2, col",
+            "    .if row == col",
+            "      out B",
+            "    .else",
+            "      nop",
+            "    .endif",
+            "  .endr",
+            ".endr",
+        ]);
+        let nop = Add(Register::A, Operand::Literal(0, "0".to_string()));
+        assert_eq!(result, vec![OutB, nop.clone(), nop, OutB]);
+    }
+
+    #[test]
+    fn parse_expansion_limit() {
+        let mut source = vec![".rept 256"; 4];
+        source.push("out 1");
+        source.extend([".endr"; 4]);
+        let errors = Parser::new(lines(&source)).parse_all().errors;
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message(),
+            "macro and `.rept` expansions produce more than 65536 lines"
+        );
+
+        let errors = Parser::new(lines(&[
+            ".macro m",
+            ".rept 256",
+            "out 1",
+            ".endr",
+            ".endm",
+            ".rept 256",
+            "m",
+            ".endr",
+        ]))
+        .parse_all()
+        .errors;
+        assert_eq!(errors.len(), 1);
+
+        // Lines inside nested blocks only count once they are expanded.
+        let mut source = vec![".rept 1"; 200];
+        source.push("out 1");
+        source.extend([".endr"; 200]);
+        let parsed = Parser::new(lines(&source)).parse_all();
+        assert!(parsed.errors.is_empty());
+        assert_eq!(parsed.tokens.len(), 1);
+    }
+
+    #[test]
+    fn parse_rept_errors() {
+        let error = |source: &[&str]| Parser::new(lines(source)).parse().unwrap_err().to_string();
+
+        assert_eq!(
+            error(&[".rept 2", "out B"]),
+            "<input>:1:1: `.rept` is missing its `.endr`"
+        );
+        assert_eq!(
+            error(&["out B", ".endr"]),
+            "<input>:2:1: `.endr` without a matching `.rept`"
+        );
+        assert_eq!(
+            error(&[".rept 0 - 1", ".endr"]),
+            "<input>:1:7: `.rept` count (-1) must be between 0 and 256"
+        );
+        assert_eq!(
+            error(&[".rept 2, A", ".endr"]),
+            "<input>:1:10: invalid `.rept` variable `A`"
+        );
+    }
+
+    #[test]
+    fn parse_all_reports_every_error() {
+        let parsed = Parser::new(lines(&[
+            "mvo A, 1",
+            "out 3",
+            "add C, 1",
+            "loop: out 1 $",
+            "jnc lop",
+        ]))
+        .parse_all();
+        let errors: Vec<String> = parsed.errors.iter().map(|err| err.to_string()).collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                "<input>:1:1: unknown mnemonic `mvo`, expected one of `mov`, `add`, `jmp`, \
+                 `jnc`, `in`, `out`, `nop`, `halt`, `clc`; did you mean `mov`?",
+                "<input>:3:5: expected register `A` or `B`, found `C`",
+                "<input>:4:13: unexpected character `$`",
+                "<input>:5:5: undefined symbol `lop`",
+            ]
+        );
+        assert_eq!(
+            parsed
+                .tokens
+                .into_iter()
+                .map(|token| token.kind)
+                .collect::<Vec<_>>(),
+            vec![OutIm(3.into()), Jnc(Operand::Symbol("lop".to_string()))]
+        );
+    }
+
+    #[test]
+    fn parse_all_skips_the_body_of_a_broken_block() {
+        let errors = |source: &[&str]| -> Vec<String> {
+            let parsed = Parser::new(lines(source)).parse_all();
+            parsed.errors.iter().map(|err| err.to_string()).collect()
+        };
+
+        assert_eq!(
+            errors(&[".macro mov p", "  bogus p", ".endm", "out 1"]),
+            vec!["<input>:1:8: `mov` is an instruction and can't be used as a macro name"]
+        );
+        assert_eq!(
+            errors(&[".rept N", "  bogus", ".endr"]),
+            vec!["<input>:1:7: `N` is not defined yet"]
+        );
+        assert_eq!(
+            errors(&[".if N", "  bogus", ".elif 1", "  bogus", ".else", "  bogus", ".endif"]),
+            vec!["<input>:1:5: `N` is not defined yet"]
+        );
+        assert_eq!(
+            errors(&[".if N + 1", ".endif", ".rept (N), i", ".endr"]),
+            vec![
+                "<input>:1:5: `N` is not defined yet",
+                "<input>:3:7: `N` is not defined yet",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_all_reads_past_a_lex_error_in_a_body() {
+        let errors = |source: &[&str]| -> Vec<String> {
+            let parsed = Parser::new(lines(source)).parse_all();
+            parsed.errors.iter().map(|err| err.to_string()).collect()
+        };
+
+        assert_eq!(
+            errors(&[".macro m", "mov A @", "out B", ".endm", "m"]),
+            vec!["<input>:2:7: unexpected character `@`"]
+        );
+        assert_eq!(
+            errors(&[".rept 2", "out $", "out B", ".endr"]),
+            vec!["<input>:2:5: unexpected character `$`"]
+        );
+    }
+
+    #[test]
+    fn parse_all_keeps_errors_in_source_order() {
+        let parsed = Parser::new(lines(&["jmp nowhere", "mvo A, 1", "jnc elsewhere"])).parse_all();
+        let errors: Vec<String> = parsed.errors.iter().map(|err| err.to_string()).collect();
+
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].starts_with("<input>:1:5: undefined symbol `nowhere`"));
+        assert!(errors[1].starts_with("<input>:2:1: unknown mnemonic `mvo`"));
+        assert!(errors[2].starts_with("<input>:3:5: undefined symbol `elsewhere`"));
+    }
 }
@@ -1,4 +1,4 @@
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Register {
     register_a: u8, // register a
     register_b: u8, // register b
@@ -6,17 +6,6 @@ pub struct Register {
     pc: u8,         // program counter
 }
 
-impl Default for Register {
-    fn default() -> Self {
-        Self {
-            register_a: u8::default(),
-            register_b: u8::default(),
-            carry_flag: u8::default(),
-            pc: u8::default(),
-        }
-    }
-}
-
 impl Register {
     pub fn new() -> Self {
         Self::default()
@@ -31,7 +20,7 @@ impl Register {
     }
 
     pub fn incr_pc(&mut self) {
-        self.pc += 1;
+        self.pc = self.pc.wrapping_add(1);
     }
 
     pub fn carry_flag(&self) -> u8 {
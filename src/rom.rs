@@ -1,17 +1,65 @@
+use crate::error::EmulatorErr;
+
+/// Number of 8-bit words the TD4 can address with its 4-bit program counter.
+pub const ROM_SIZE: usize = 16;
+
 pub struct Rom {
     pub memory_array: Vec<u8>,
 }
 
 impl Rom {
+    /// Wraps `memory_array` without checking its size; `CpuEmulator`
+    /// rejects a ROM that is too large. See `try_new`.
     pub fn new(memory_array: Vec<u8>) -> Self {
         Self { memory_array }
     }
 
-    pub fn read(&self, pc: u8) -> u8 {
-        self.memory_array[pc as usize]
+    /// Like `new`, but fails if the program doesn't fit in `ROM_SIZE` words.
+    pub fn try_new(memory_array: Vec<u8>) -> Result<Self, EmulatorErr> {
+        if memory_array.len() > ROM_SIZE {
+            return Err(EmulatorErr::RomOverflow {
+                size: memory_array.len(),
+                msg: format!(
+                    "program needs {} words but the ROM only holds {}",
+                    memory_array.len(),
+                    ROM_SIZE
+                ),
+                span: None,
+                annotations: Box::default(),
+            });
+        }
+        Ok(Self::new(memory_array))
     }
 
+    /// The word at `pc`, or `None` past the end of the program.
+    pub fn read(&self, pc: u8) -> Option<u8> {
+        self.memory_array.get(pc as usize).copied()
+    }
+
+    /// The number of words in the program, saturating at 255.
     pub fn size(&self) -> u8 {
-        self.memory_array.len() as u8
+        u8::try_from(self.memory_array.len()).unwrap_or(u8::MAX)
+    }
+}
+
+#[cfg(test)]
+mod rom_tests {
+    use crate::error::EmulatorErr;
+    use crate::rom::{Rom, ROM_SIZE};
+
+    #[test]
+    fn test_read_past_end() {
+        let rom = Rom::new(vec![0b0011_0001]);
+        assert_eq!(rom.read(0), Some(0b0011_0001));
+        assert_eq!(rom.read(1), None);
+        assert_eq!(rom.read(u8::MAX), None);
+    }
+
+    #[test]
+    fn test_try_new() {
+        assert!(Rom::try_new(vec![0; ROM_SIZE]).is_ok());
+        let err = Rom::try_new(vec![0; 300]).err().unwrap();
+        assert!(matches!(err, EmulatorErr::RomOverflow { size: 300, .. }));
+        assert_eq!(Rom::new(vec![0; 300]).size(), u8::MAX);
     }
 }
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// A location in a source file. Lines and columns are 1-based and
+/// `end_column` is exclusive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub file: Arc<str>,
+    pub line: usize,
+    pub column: usize,
+    pub end_column: usize,
+    /// Set when the text comes from the body of a macro, pointing at the
+    /// invocation that expanded it.
+    pub expansion: Option<Arc<Expansion>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expansion {
+    pub macro_name: String,
+    pub call_site: Span,
+}
+
+impl Span {
+    pub fn new(file: Arc<str>, line: usize, column: usize, end_column: usize) -> Self {
+        Self {
+            file,
+            line,
+            column,
+            end_column,
+            expansion: None,
+        }
+    }
+
+    /// The chain of macro invocations that produced this span, innermost
+    /// first.
+    pub fn backtrace(&self) -> Vec<&Expansion> {
+        let mut expansions = Vec::new();
+        let mut current = self.expansion.as_deref();
+        while let Some(expansion) = current {
+            expansions.push(expansion);
+            current = expansion.call_site.expansion.as_deref();
+        }
+        expansions
+    }
+
+    /// Covers everything from the start of `self` to the end of `other`,
+    /// which must lie on the same line.
+    pub fn to(&self, other: &Span) -> Span {
+        Span {
+            end_column: other.end_column,
+            ..self.clone()
+        }
+    }
+}
+
+impl Default for Span {
+    fn default() -> Self {
+        Self::new(Arc::from("<input>"), 0, 0, 0)
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// The text of every file that took part in a build, so that diagnostics
+/// can quote the lines a `Span` points at.
+#[derive(Debug, Default, Clone)]
+pub struct SourceMap {
+    files: HashMap<Arc<str>, Vec<String>>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, file: Arc<str>, lines: Vec<String>) {
+        self.files.insert(file, lines);
+    }
+
+    pub fn line(&self, span: &Span) -> Option<&str> {
+        let lines = self.files.get(&span.file)?;
+        let index = span.line.checked_sub(1)?;
+        lines.get(index).map(|line| line.as_str())
+    }
+}
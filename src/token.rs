@@ -1,30 +1,496 @@
-#[derive(Debug)]
+use crate::error::EmulatorErr;
+use crate::lexer::format_decimal;
+use crate::loader::MemoryLoader;
+use crate::op::Opcode;
+use crate::parser::Parser;
+use crate::span::Span;
+use std::fmt;
+use std::str::FromStr;
+
+/// How many constants deep a definition may refer to other constants. Their
+/// values are worked out recursively, so this keeps the stack bounded.
+pub(crate) const MAX_CONSTANT_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Register {
     A,
     B,
 }
 
-impl From<String> for Register {
-    fn from(a: String) -> Self {
-        if a == *"A" {
-            Register::A
-        } else if a == *"B" {
-            Register::B
-        } else {
-            panic!("couldn't parse")
+impl TryFrom<&str> for Register {
+    type Error = EmulatorErr;
+
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        match name {
+            "A" => Ok(Register::A),
+            "B" => Ok(Register::B),
+            other => Err(EmulatorErr::Parse {
+                msg: format!("expected register `A` or `B`, found `{}`", other),
+                span: None,
+                annotations: Box::default(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<String> for Register {
+    type Error = EmulatorErr;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        Register::try_from(name.as_str())
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::A => write!(f, "A"),
+            Register::B => write!(f, "B"),
+        }
+    }
+}
+
+/// The immediate part of an instruction: a number, a symbol or a constant
+/// expression combining them. Symbols stay unresolved until
+/// `Compiler::compile` knows the address of every label, and the range of
+/// every operand is checked there too.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    /// A numeric literal, along with the text it was written as.
+    Literal(u32, String),
+    Symbol(String),
+    Unary(UnaryOp, Box<Operand>),
+    Binary(BinaryOp, Box<Operand>, Box<Operand>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    /// `!`: 1 if the operand is 0, and 0 otherwise.
+    LogicalNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    And,
+    Xor,
+    Or,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    LogicalAnd,
+    LogicalOr,
+}
+
+impl UnaryOp {
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        match symbol {
+            "-" => Some(UnaryOp::Neg),
+            "~" => Some(UnaryOp::Not),
+            "!" => Some(UnaryOp::LogicalNot),
+            _ => None,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            UnaryOp::Neg => "-",
+            UnaryOp::Not => "~",
+            UnaryOp::LogicalNot => "!",
+        }
+    }
+}
+
+impl BinaryOp {
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        let op = match symbol {
+            "*" => BinaryOp::Mul,
+            "/" => BinaryOp::Div,
+            "%" => BinaryOp::Rem,
+            "+" => BinaryOp::Add,
+            "-" => BinaryOp::Sub,
+            "<<" => BinaryOp::Shl,
+            ">>" => BinaryOp::Shr,
+            "&" => BinaryOp::And,
+            "^" => BinaryOp::Xor,
+            "|" => BinaryOp::Or,
+            "==" => BinaryOp::Eq,
+            "!=" => BinaryOp::Ne,
+            "<" => BinaryOp::Lt,
+            "<=" => BinaryOp::Le,
+            ">" => BinaryOp::Gt,
+            ">=" => BinaryOp::Ge,
+            "&&" => BinaryOp::LogicalAnd,
+            "||" => BinaryOp::LogicalOr,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::And => "&",
+            BinaryOp::Xor => "^",
+            BinaryOp::Or => "|",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::LogicalAnd => "&&",
+            BinaryOp::LogicalOr => "||",
+        }
+    }
+
+    /// Binding strength, C style: higher binds tighter.
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 9,
+            BinaryOp::Add | BinaryOp::Sub => 8,
+            BinaryOp::Shl | BinaryOp::Shr => 7,
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 6,
+            BinaryOp::Eq | BinaryOp::Ne => 5,
+            BinaryOp::And => 4,
+            BinaryOp::Xor => 3,
+            BinaryOp::Or => 2,
+            BinaryOp::LogicalAnd => 1,
+            BinaryOp::LogicalOr => 0,
+        }
+    }
+
+    fn apply(self, lhs: i64, rhs: i64) -> Option<i64> {
+        match self {
+            BinaryOp::Mul => lhs.checked_mul(rhs),
+            BinaryOp::Div => lhs.checked_div(rhs),
+            BinaryOp::Rem => lhs.checked_rem(rhs),
+            BinaryOp::Add => lhs.checked_add(rhs),
+            BinaryOp::Sub => lhs.checked_sub(rhs),
+            BinaryOp::Shl => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shl(rhs)),
+            BinaryOp::Shr => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_shr(rhs)),
+            BinaryOp::And => Some(lhs & rhs),
+            BinaryOp::Xor => Some(lhs ^ rhs),
+            BinaryOp::Or => Some(lhs | rhs),
+            BinaryOp::Eq => Some(i64::from(lhs == rhs)),
+            BinaryOp::Ne => Some(i64::from(lhs != rhs)),
+            BinaryOp::Lt => Some(i64::from(lhs < rhs)),
+            BinaryOp::Le => Some(i64::from(lhs <= rhs)),
+            BinaryOp::Gt => Some(i64::from(lhs > rhs)),
+            BinaryOp::Ge => Some(i64::from(lhs >= rhs)),
+            BinaryOp::LogicalAnd => Some(i64::from(lhs != 0 && rhs != 0)),
+            BinaryOp::LogicalOr => Some(i64::from(lhs != 0 || rhs != 0)),
+        }
+    }
+}
+
+impl Operand {
+    /// Computes the value of the operand, asking `lookup` for the value of
+    /// every symbol. Errors are reported at `span`.
+    pub fn evaluate(
+        &self,
+        span: &Span,
+        lookup: &mut dyn FnMut(&str) -> Result<i64, EmulatorErr>,
+    ) -> Result<i64, EmulatorErr> {
+        match self {
+            Operand::Literal(value, _) => Ok(i64::from(*value)),
+            Operand::Symbol(name) => lookup(name),
+            Operand::Unary(op, operand) => {
+                let value = operand.evaluate(span, lookup)?;
+                match op {
+                    UnaryOp::Neg => value.checked_neg().ok_or_else(|| {
+                        EmulatorErr::assembly(&format!("`-{}` overflows", value), span.clone())
+                    }),
+                    UnaryOp::Not => Ok(!value),
+                    UnaryOp::LogicalNot => Ok(i64::from(value == 0)),
+                }
+            }
+            Operand::Binary(op, lhs, rhs) => {
+                let lhs = lhs.evaluate(span, lookup)?;
+                let rhs = rhs.evaluate(span, lookup)?;
+                op.apply(lhs, rhs).ok_or_else(|| {
+                    EmulatorErr::assembly(
+                        &format!("`{} {} {}` can't be evaluated", lhs, op.symbol(), rhs),
+                        span.clone(),
+                    )
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Literal(_, text) => write!(f, "{}", text),
+            Operand::Symbol(name) => write!(f, "{}", name),
+            Operand::Unary(op, operand) => match **operand {
+                Operand::Binary(..) => write!(f, "{}({})", op.symbol(), operand),
+                _ => write!(f, "{}{}", op.symbol(), operand),
+            },
+            Operand::Binary(op, lhs, rhs) => {
+                // Parenthesise children that would otherwise bind differently
+                // when the text is parsed again.
+                let needs_parens = |child: &Operand, right: bool| match child {
+                    Operand::Binary(child_op, ..) => {
+                        child_op.precedence() < op.precedence()
+                            || (right && child_op.precedence() == op.precedence())
+                    }
+                    _ => false,
+                };
+                if needs_parens(lhs, false) {
+                    write!(f, "({})", lhs)?;
+                } else {
+                    write!(f, "{}", lhs)?;
+                }
+                write!(f, " {} ", op.symbol())?;
+                if needs_parens(rhs, true) {
+                    write!(f, "({})", rhs)
+                } else {
+                    write!(f, "{}", rhs)
+                }
+            }
         }
     }
 }
 
-#[derive(Debug)]
-pub enum Token {
-    Mov(Register, u8),
+impl From<u8> for Operand {
+    fn from(value: u8) -> Self {
+        Operand::Literal(value.into(), format_decimal(value.into()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Label(String),
+    /// `.equ NAME value`; like a label, it emits no code.
+    Constant(String, Operand),
+    /// `.org address`: the following code is placed from `address` on.
+    Org(Operand),
+    /// `.db value`: a raw byte.
+    Db(Operand),
+    Mov(Register, Operand),
     MovAB,
     MovBA,
-    Add(Register, u8),
-    Jmp(u8),
-    Jnc(u8),
+    Add(Register, Operand),
+    Jmp(Operand),
+    Jnc(Operand),
     In(Register),
-    OutIm(u8),
+    OutIm(Operand),
     OutB,
 }
+
+impl TokenKind {
+    /// Whether this token takes up a word of ROM.
+    pub fn is_instruction(&self) -> bool {
+        !matches!(
+            self,
+            TokenKind::Label(_) | TokenKind::Constant(..) | TokenKind::Org(_)
+        )
+    }
+
+    /// The opcode of a machine instruction along with its immediate, if the
+    /// opcode takes one. Labels and directives have no opcode.
+    pub fn instruction(&self) -> Option<(Opcode, Option<&Operand>)> {
+        let instruction = match self {
+            TokenKind::Label(_)
+            | TokenKind::Constant(..)
+            | TokenKind::Org(_)
+            | TokenKind::Db(_) => return None,
+            TokenKind::Mov(Register::A, im) => (Opcode::MovA, Some(im)),
+            TokenKind::Mov(Register::B, im) => (Opcode::MovB, Some(im)),
+            TokenKind::MovAB => (Opcode::MovA2B, None),
+            TokenKind::MovBA => (Opcode::MovB2A, None),
+            TokenKind::Add(Register::A, im) => (Opcode::AddA, Some(im)),
+            TokenKind::Add(Register::B, im) => (Opcode::AddB, Some(im)),
+            TokenKind::Jmp(target) => (Opcode::Jmp, Some(target)),
+            TokenKind::Jnc(target) => (Opcode::Jnc, Some(target)),
+            TokenKind::In(Register::A) => (Opcode::InA, None),
+            TokenKind::In(Register::B) => (Opcode::InB, None),
+            TokenKind::OutB => (Opcode::OutB, None),
+            TokenKind::OutIm(im) => (Opcode::OutIm, Some(im)),
+        };
+        Some(instruction)
+    }
+
+    /// The token for `opcode`. `im` is dropped if the opcode doesn't take an
+    /// immediate.
+    pub fn from_instruction(opcode: Opcode, im: Operand) -> TokenKind {
+        match opcode {
+            Opcode::MovA => TokenKind::Mov(Register::A, im),
+            Opcode::MovB => TokenKind::Mov(Register::B, im),
+            Opcode::MovA2B => TokenKind::MovAB,
+            Opcode::MovB2A => TokenKind::MovBA,
+            Opcode::AddA => TokenKind::Add(Register::A, im),
+            Opcode::AddB => TokenKind::Add(Register::B, im),
+            Opcode::Jmp => TokenKind::Jmp(im),
+            Opcode::Jnc => TokenKind::Jnc(im),
+            Opcode::InA => TokenKind::In(Register::A),
+            Opcode::InB => TokenKind::In(Register::B),
+            Opcode::OutB => TokenKind::OutB,
+            Opcode::OutIm => TokenKind::OutIm(im),
+        }
+    }
+}
+
+/// The canonical source text for the token, which the parser reads back
+/// as the same token.
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenKind::Label(name) => write!(f, "{}:", name),
+            TokenKind::Constant(name, value) => write!(f, ".equ {} {}", name, value),
+            TokenKind::Org(address) => write!(f, ".org {}", address),
+            TokenKind::Db(value) => write!(f, ".db {}", value),
+            TokenKind::Mov(register, im) => write!(f, "mov {}, {}", register, im),
+            TokenKind::MovAB => write!(f, "mov A, B"),
+            TokenKind::MovBA => write!(f, "mov B, A"),
+            TokenKind::Add(register, im) => write!(f, "add {}, {}", register, im),
+            TokenKind::Jmp(target) => write!(f, "jmp {}", target),
+            TokenKind::Jnc(target) => write!(f, "jnc {}", target),
+            TokenKind::In(register) => write!(f, "in {}", register),
+            TokenKind::OutIm(im) => write!(f, "out {}", im),
+            TokenKind::OutB => write!(f, "out B"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl Token {
+    pub fn new(kind: TokenKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)
+    }
+}
+
+/// Parses a single line holding one instruction, label or directive, such
+/// as `jnc loop`. Symbols don't have to be defined, and spans point into
+/// the line as file `<input>`. Nothing is read from disk: `.include` fails.
+/// Pseudo-instructions that stand for more than one token, such as `halt`,
+/// `clc` and `out A`, are rejected.
+impl FromStr for Token {
+    type Err = EmulatorErr;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Parser::new(vec![line.to_string()])
+            .with_loader(MemoryLoader::new())
+            .parse_unchecked()?;
+        match tokens.len() {
+            1 => Ok(tokens.remove(0)),
+            0 => Err(EmulatorErr::Parse {
+                msg: format!("`{}` holds no instruction", line.trim()),
+                span: None,
+                annotations: Box::default(),
+            }),
+            _ => Err(EmulatorErr::Parse {
+                msg: format!("`{}` stands for more than one token", line.trim()),
+                span: None,
+                annotations: Box::default(),
+            }),
+        }
+    }
+}
+
+/// Builds a token that doesn't come from any source file.
+impl From<TokenKind> for Token {
+    fn from(kind: TokenKind) -> Self {
+        Self::new(kind, Span::default())
+    }
+}
+
+#[cfg(test)]
+mod token_tests {
+    use crate::error::EmulatorErr;
+    use crate::token::{BinaryOp, Operand, Register, Token, TokenKind};
+
+    #[test]
+    fn test_display_token() {
+        let shifted = Operand::Binary(
+            BinaryOp::Shl,
+            Box::new(Operand::Symbol("LED".to_string())),
+            Box::new(1.into()),
+        );
+        let cases = [
+            (TokenKind::Label("loop".to_string()), "loop:"),
+            (
+                TokenKind::Constant("LED".to_string(), 3.into()),
+                ".equ LED 3",
+            ),
+            (TokenKind::Org(4.into()), ".org 4"),
+            (TokenKind::Db(0x80.into()), ".db 128"),
+            (TokenKind::Mov(Register::B, 1.into()), "mov B, 1"),
+            (TokenKind::MovAB, "mov A, B"),
+            (TokenKind::Add(Register::A, shifted), "add A, LED << 1"),
+            (
+                TokenKind::Jnc(Operand::Symbol("loop".to_string())),
+                "jnc loop",
+            ),
+            (TokenKind::In(Register::B), "in B"),
+            (TokenKind::OutB, "out B"),
+        ];
+
+        for (kind, text) in cases {
+            assert_eq!(kind.to_string(), text);
+            let parsed: Token = text.parse().unwrap();
+            assert_eq!(parsed.kind, kind);
+        }
+    }
+
+    #[test]
+    fn test_parse_token() {
+        let token: Token = "  jmp done ; forward reference".parse().unwrap();
+        assert_eq!(
+            token.kind,
+            TokenKind::Jmp(Operand::Symbol("done".to_string()))
+        );
+        assert_eq!((token.span.column, token.span.end_column), (3, 11));
+
+        for line in ["halt", "clc", "out A"] {
+            let err = line.parse::<Token>().unwrap_err();
+            assert_eq!(
+                err.message(),
+                format!("`{}` stands for more than one token", line)
+            );
+        }
+        let err = ".include \"Cargo.toml\"".parse::<Token>().unwrap_err();
+        assert!(matches!(err, EmulatorErr::Io { .. }));
+        let err = "; nothing".parse::<Token>().unwrap_err();
+        assert_eq!(err.message(), "`; nothing` holds no instruction");
+        assert!("mov C, 1".parse::<Token>().is_err());
+    }
+
+    #[test]
+    fn test_register_try_from() {
+        assert_eq!(Register::try_from("B").unwrap(), Register::B);
+        assert_eq!(Register::try_from("A".to_string()).unwrap(), Register::A);
+        let err = Register::try_from("C").unwrap_err();
+        assert_eq!(err.message(), "expected register `A` or `B`, found `C`");
+    }
+}